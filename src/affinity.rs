@@ -0,0 +1,211 @@
+use std::env;
+use std::io;
+use std::thread;
+
+use crate::ClockFn;
+
+/// Parses a Linux-style CPU list such as `0-3,8,10-11`.
+pub fn parse_cpu_list(s: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = Vec::new();
+    for part in s.split(',').filter(|p| !p.is_empty()) {
+        let bad = |e: std::num::ParseIntError| format!("bad CPU list {s:?}: {e}");
+        match part.split_once('-') {
+            Some((lo, hi)) => {
+                let lo = lo.trim().parse::<usize>().map_err(bad)?;
+                let hi = hi.trim().parse::<usize>().map_err(bad)?;
+                if hi < lo {
+                    return Err(format!("bad CPU list {s:?}: range {lo}-{hi} is backwards"));
+                }
+                cpus.extend(lo..=hi);
+            }
+            None => cpus.push(part.trim().parse::<usize>().map_err(bad)?),
+        }
+    }
+    if cpus.is_empty() {
+        return Err(format!("empty CPU list {s:?}"));
+    }
+    Ok(cpus)
+}
+
+/// Returns the CPUs this process is allowed to run on.
+#[cfg(target_os = "linux")]
+pub fn online_cpus() -> Vec<usize> {
+    use crate::plat_unixes::libc;
+    use std::mem::MaybeUninit;
+
+    let mut set: MaybeUninit<libc::cpu_set_t> = MaybeUninit::zeroed();
+    let retval = unsafe { libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), set.as_mut_ptr()) };
+    assert_eq!(retval, 0, "sched_getaffinity: {}", io::Error::last_os_error());
+    let set = unsafe { set.assume_init() };
+
+    (0..libc::CPU_SETSIZE as usize).filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) }).collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn online_cpus() -> Vec<usize> {
+    (0..thread::available_parallelism().unwrap().get()).collect()
+}
+
+/// Pins the calling thread to a single CPU.
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpu: usize) -> io::Result<()> {
+    use crate::plat_unixes::libc;
+    use std::mem::MaybeUninit;
+
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("CPU {cpu} is out of range")));
+    }
+
+    let mut set: MaybeUninit<libc::cpu_set_t> = MaybeUninit::zeroed();
+    let mut set = unsafe {
+        libc::CPU_ZERO(set.assume_init_mut());
+        set.assume_init()
+    };
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+
+    let retval = unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) };
+    if retval == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_current_thread(_cpu: usize) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "pinning threads to CPUs is only implemented on Linux"))
+}
+
+/// Returns the CPU the calling thread is running on right now, if the platform can tell us.
+#[cfg(target_os = "linux")]
+pub fn current_cpu() -> Option<usize> {
+    use crate::plat_unixes::libc;
+
+    let cpu = unsafe { libc::sched_getcpu() };
+    if cpu >= 0 { Some(cpu as usize) } else { None }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn current_cpu() -> Option<usize> {
+    None
+}
+
+/// Returns another hardware thread on the same core as `cpu`, if SMT is on and there is one.
+#[cfg(target_os = "linux")]
+pub fn smt_sibling(cpu: usize) -> Option<usize> {
+    let path = format!("/sys/devices/system/cpu/cpu{cpu}/topology/thread_siblings_list");
+    let siblings = std::fs::read_to_string(path).ok()?;
+    parse_cpu_list(siblings.trim()).ok()?.into_iter().find(|&c| c != cpu)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn smt_sibling(_cpu: usize) -> Option<usize> {
+    None
+}
+
+/// Formats the CPU a measurement started and finished on: `3` if it stayed put, `3>5` if it was
+/// migrated somewhere along the way, or `-` if we can't tell.
+pub fn format_cpus(start: Option<usize>, end: Option<usize>) -> String {
+    match (start, end) {
+        (Some(s), Some(e)) if s == e => s.to_string(),
+        (Some(s), Some(e)) => format!("{s}>{e}"),
+        _ => "-".to_string(),
+    }
+}
+
+/// How measurement threads are placed on CPUs.
+pub enum Pin {
+    /// Not pinned; the scheduler puts them wherever it likes (the default).
+    Float,
+    /// The n'th measurement thread overall goes on the n'th allowed CPU, wrapping around.
+    RoundRobin,
+    /// Every measurement thread goes on this one CPU.
+    One(usize),
+}
+
+pub struct Placement {
+    pub pin: Pin,
+    /// `--pin-clock=CLOCK=CPULIST` overrides, which take precedence over `pin` for matching clocks.
+    pub perclock: Vec<(String, Vec<usize>)>,
+    pub cpus: Vec<usize>,
+}
+
+impl Placement {
+    /// Returns the CPU to pin a thread to, given the clock it measures, its index among that
+    /// clock's threads, and its index among all measurement threads.
+    pub fn cpu_for(&self, cf: &ClockFn, threadidx: usize, globalidx: usize) -> Option<usize> {
+        if let Some((_, cpus)) = self.perclock.iter().find(|(name, _)| clock_matches(cf, name)) {
+            return Some(cpus[threadidx % cpus.len()]);
+        }
+
+        self.cpu_for_all_clocks(globalidx)
+    }
+
+    /// Returns the CPU to pin a thread that measures every clock to, ignoring `--pin-clock`.
+    pub fn cpu_for_all_clocks(&self, globalidx: usize) -> Option<usize> {
+        match self.pin {
+            Pin::Float => None,
+            Pin::RoundRobin => Some(self.cpus[globalidx % self.cpus.len()]),
+            Pin::One(cpu) => Some(cpu),
+        }
+    }
+}
+
+/// A `--pin-clock` name matches a clock if it is the clock name (e.g. `MONOTONIC`), the function
+/// name (e.g. `plat_x86_64::rdtscp`), or the last part of the function name (e.g. `rdtscp`).
+fn clock_matches(cf: &ClockFn, name: &str) -> bool {
+    name == cf.clockname || name == cf.fnname || cf.fnname.rsplit("::").next() == Some(name)
+}
+
+/// Reads `--pin=rr`, `--pin=CPU` and any number of `--pin-clock=CLOCK=CPULIST`.
+pub fn get_placement() -> Placement {
+    let mut pin = Pin::Float;
+    let mut perclock = Vec::new();
+
+    for arg in env::args() {
+        if let Some(pinstr) = arg.strip_prefix("--pin=") {
+            pin = match pinstr {
+                "rr" | "roundrobin" => Pin::RoundRobin,
+                "none" => Pin::Float,
+                cpustr => match cpustr.parse::<usize>() {
+                    Ok(cpu) => Pin::One(cpu),
+                    Err(_) => panic!("--pin= takes rr, none, or a CPU number, not {cpustr:?}"),
+                },
+            };
+        } else if let Some(pcstr) = arg.strip_prefix("--pin-clock=") {
+            let Some((name, cpustr)) = pcstr.split_once('=') else {
+                panic!("--pin-clock= takes CLOCK=CPULIST, e.g. --pin-clock=MONOTONIC=0-3, not {pcstr:?}");
+            };
+            match parse_cpu_list(cpustr) {
+                Ok(cpus) => perclock.push((name.to_string(), cpus)),
+                Err(e) => panic!("{e}"),
+            }
+        }
+    }
+
+    Placement { pin, perclock, cpus: online_cpus() }
+}
+
+/// Returns how many measurement threads to run per clock: `--threads=N` if given, otherwise twice
+/// the available parallelism with `--overthread`, otherwise 1. Returns an error if N isn't a
+/// number of at least 1.
+pub fn get_threads_per_clock() -> Result<usize, String> {
+    let args: Vec<String> = env::args().collect();
+
+    for arg in &args {
+        if let Some(threads_str) = arg.strip_prefix("--threads=") {
+            return match threads_str.parse::<usize>() {
+                Ok(argthreads) if argthreads >= 1 => Ok(argthreads),
+                _ => Err(format!("--threads=N needs a number of at least 1, not {threads_str:?}")),
+            };
+        }
+    }
+
+    if args.contains(&"--overthread".to_string()) {
+        let count = thread::available_parallelism().unwrap().get();
+        assert!(count >= 1_usize);
+        Ok(count * 2)
+    } else {
+        Ok(1)
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::sync::OnceLock;
+
+use thousands::Separable;
+
+use crate::histogram::Recorder;
+use crate::{summarize, ClockFn, RunInfo, Summary};
+
+/// Returns whether `--per-thread` was given, to show every thread's results under a clock's
+/// aggregate row.
+pub fn get_per_thread() -> bool {
+    static PER_THREAD: OnceLock<bool> = OnceLock::new();
+    *PER_THREAD.get_or_init(|| env::args().any(|arg| arg == "--per-thread"))
+}
+
+/// Merges every thread's measurement of one clock into a single summary, as if one thread had
+/// taken all of their samples. The threads' own summaries are kept, in thread order, for the
+/// spread between them, `--per-thread`, and their periods (samples from different threads don't
+/// follow on from each other, so the aggregate has none). A single thread's summary is returned
+/// as it is.
+pub fn aggregate(cf: &ClockFn, mut results: Vec<(Summary, Recorder)>) -> Summary {
+    if results.len() == 1 {
+        return results.pop().unwrap().0;
+    }
+
+    let mut rec = results[0].1.clone();
+    let mut calibration = results[0].0.calibration.clone();
+    for (s, r) in &results[1..] {
+        rec.merge(r);
+        calibration.merge(&s.calibration);
+    }
+
+    let mut overheads: Vec<u64> = results.iter().filter_map(|(s, _)| s.overhead).collect();
+    overheads.sort_unstable();
+    let overhead = overheads.get(overheads.len() / 2).copied();
+
+    let mut threads: Vec<Summary> = results.into_iter().map(|(s, _)| s).collect();
+    let run = RunInfo {
+        cpus: merge_cpus(threads.iter().map(|s| s.run.cpus.as_str())),
+        started: threads.iter().map(|s| s.run.started).min().unwrap(),
+        finished: threads.iter().map(|s| s.run.finished).max().unwrap(),
+    };
+
+    let mut summary = summarize(cf, calibration, overhead, &rec, run);
+    for s in &mut threads {
+        if let Some(c) = s.classes.take() {
+            match &mut summary.classes {
+                Some(classes) => classes.merge(&c),
+                None => summary.classes = Some(c),
+            }
+        }
+        if let Some(o) = s.outliers.take() {
+            match &mut summary.outliers {
+                Some(outliers) => outliers.merge(o),
+                None => summary.outliers = Some(o),
+            }
+        }
+    }
+    summary.threads = threads;
+    summary
+}
+
+/// The CPUs the threads ran on, for an aggregate row's cpu column: the CPU if they all ran on the
+/// same one, otherwise how many different ones they ran on.
+pub fn merge_cpus<'a>(cpus: impl Iterator<Item = &'a str>) -> String {
+    let cpus: Vec<&str> = cpus.collect();
+    if cpus.iter().all(|c| *c == cpus[0]) {
+        return cpus[0].to_string();
+    }
+    let distinct: BTreeSet<&str> = cpus.iter().flat_map(|c| c.split('>')).filter(|c| *c != "-").collect();
+    format!("{}cpus", distinct.len())
+}
+
+/// Describes how much the threads' results differ from each other, for a line under the
+/// aggregate row.
+pub fn describe_spread(threads: &[Summary]) -> String {
+    let range = |f: fn(&Summary) -> u64| {
+        let lo = threads.iter().map(f).min().unwrap_or(0);
+        let hi = threads.iter().map(f).max().unwrap_or(0);
+        format!("{}..{}", lo.separate_with_commas(), hi.separate_with_commas())
+    };
+    format!("threads: {}; between them: perc50 {} perc95 {} max {} stddev {}ns", threads.len(), range(|s| s.perc50), range(|s| s.perc95), range(|s| s.max), range(|s| s.stddev as u64))
+}
+
+/// Describes one thread's results, for a line under the aggregate row with `--per-thread`.
+pub fn describe_thread(i: usize, s: &Summary) -> String {
+    format!("thread {i} (cpu {}): n {} min {} perc50 {} mean {} perc95 {} max {} stddev {}ns", s.run.cpus, s.numsamples.separate_with_commas(), s.min.separate_with_commas(), s.perc50.separate_with_commas(), s.mean.separate_with_commas(), s.perc95.separate_with_commas(), s.max.separate_with_commas(), (s.stddev as u128).separate_with_commas())
+}
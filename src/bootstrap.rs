@@ -0,0 +1,192 @@
+use std::env;
+use std::sync::OnceLock;
+
+use crate::histogram::Recorder;
+
+const DEFAULT_RESAMPLES: u32 = 1000;
+const DEFAULT_LEVEL: f64 = 0.95;
+
+/// Above this many samples in a bucket, its Poisson weight is drawn from the normal approximation.
+const POISSON_NORMAL_ABOVE: u64 = 30;
+
+/// Settings for `--bootstrap`.
+#[derive(Clone, Copy, Debug)]
+pub struct Bootstrap {
+    pub resamples: u32,
+    /// The confidence level, e.g. 0.95.
+    pub level: f64,
+}
+
+/// Returns the `--bootstrap` or `--bootstrap=RESAMPLES` settings, with the level from
+/// `--ci=PERCENT` (95 if not given), or None if bootstrapping is off.
+pub fn get_bootstrap() -> Option<Bootstrap> {
+    static BOOTSTRAP: OnceLock<Option<Bootstrap>> = OnceLock::new();
+
+    *BOOTSTRAP.get_or_init(|| {
+        let mut resamples = None;
+        let mut level = DEFAULT_LEVEL;
+        for arg in env::args() {
+            if arg == "--bootstrap" {
+                resamples = Some(DEFAULT_RESAMPLES);
+            } else if let Some(bsstr) = arg.strip_prefix("--bootstrap=") {
+                match bsstr.parse::<u32>() {
+                    Ok(n) if n >= 10 => resamples = Some(n),
+                    _ => panic!("--bootstrap= needs a number of resamples of at least 10, not {bsstr:?}"),
+                }
+            } else if let Some(cistr) = arg.strip_prefix("--ci=") {
+                match cistr.parse::<f64>() {
+                    Ok(perc) if perc > 0.0 && perc < 100.0 => level = perc / 100.0,
+                    _ => panic!("--ci= needs a confidence level between 0 and 100, e.g. --ci=99, not {cistr:?}"),
+                }
+            }
+        }
+        resamples.map(|resamples| Bootstrap { resamples, level })
+    })
+}
+
+/// A small, fast, deterministic PRNG (xorshift64*). Good enough for resampling; not for anything
+/// that needs to be unpredictable.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed | 1)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal, by Box-Muller.
+    pub fn next_normal(&mut self) -> f64 {
+        let u1 = 1.0 - self.next_f64();
+        let u2 = self.next_f64();
+        (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+    }
+
+    /// Poisson with mean `lambda`: Knuth's method for small means, the normal approximation for
+    /// large ones.
+    pub fn next_poisson(&mut self, lambda: u64) -> u64 {
+        if lambda > POISSON_NORMAL_ABOVE {
+            let l = lambda as f64;
+            return (l + l.sqrt() * self.next_normal()).round().max(0.0) as u64;
+        }
+        let limit = (-(lambda as f64)).exp();
+        let mut k = 0;
+        let mut p = self.next_f64();
+        while p > limit {
+            k += 1;
+            p *= self.next_f64();
+        }
+        k
+    }
+}
+
+/// The bootstrapped confidence interval of one statistic.
+pub struct Interval {
+    pub name: String,
+    pub lo: f64,
+    pub hi: f64,
+}
+
+/// Computes percentile-bootstrap confidence intervals for the mean, the median, each of
+/// `quantiles` (given as (label, quantile) pairs) and the standard deviation.
+///
+/// This is the Poisson bootstrap: instead of drawing n samples with replacement, each sample gets
+/// a Poisson(1) weight, which comes to the same thing for large n. A histogram bucket holding c
+/// samples then gets a Poisson(c) weight, so a resample costs time in proportion to the number of
+/// buckets rather than the number of samples. Values are the buckets' values, so the intervals
+/// have the histogram's resolution.
+pub fn intervals(rec: &Recorder, quantiles: &[(String, f64)], bs: &Bootstrap) -> Vec<Interval> {
+    let buckets: Vec<(u64, u64)> = rec.hist.iter().collect();
+    if rec.count < 2 {
+        return Vec::new();
+    }
+
+    let mut stats: Vec<(String, Stat)> = vec![("mean".to_string(), Stat::Mean), ("perc50".to_string(), Stat::Quantile(0.5))];
+    for (label, q) in quantiles {
+        if *q != 0.5 {
+            stats.push((format!("perc{label}"), Stat::Quantile(*q)));
+        }
+    }
+    stats.push(("stddev".to_string(), Stat::Stddev));
+
+    // Seeded from the data, so the same samples always give the same intervals.
+    let mut rng = Rng::new(rec.count ^ rec.max.rotate_left(32) ^ 0x9E37_79B9_7F4A_7C15);
+    let mut results: Vec<Vec<f64>> = vec![Vec::with_capacity(bs.resamples as usize); stats.len()];
+    let mut weights: Vec<u64> = vec![0; buckets.len()];
+    for _ in 0..bs.resamples {
+        let mut n = 0;
+        for (w, (_, c)) in weights.iter_mut().zip(&buckets) {
+            *w = rng.next_poisson(*c);
+            n += *w;
+        }
+        if n < 2 {
+            continue;
+        }
+        for ((_, stat), res) in stats.iter().zip(results.iter_mut()) {
+            res.push(stat.of(&buckets, &weights, n));
+        }
+    }
+
+    let alpha = (1.0 - bs.level) / 2.0;
+    stats
+        .into_iter()
+        .zip(results)
+        .filter(|(_, res)| !res.is_empty())
+        .map(|((name, _), mut res)| {
+            res.sort_by(f64::total_cmp);
+            let at = |q: f64| res[((res.len() - 1) as f64 * q).round() as usize];
+            Interval { name, lo: at(alpha), hi: at(1.0 - alpha) }
+        })
+        .collect()
+}
+
+enum Stat {
+    Mean,
+    Quantile(f64),
+    Stddev,
+}
+
+impl Stat {
+    /// Computes the statistic of the resample in which bucket i's value appears `weights[i]` times,
+    /// `n` times in all.
+    fn of(&self, buckets: &[(u64, u64)], weights: &[u64], n: u64) -> f64 {
+        let mean = || buckets.iter().zip(weights).map(|((v, _), w)| *v as f64 * *w as f64).sum::<f64>() / n as f64;
+        match *self {
+            Stat::Mean => mean(),
+            Stat::Stddev => {
+                let m = mean();
+                let ss: f64 = buckets.iter().zip(weights).map(|((v, _), w)| (*v as f64 - m).powi(2) * *w as f64).sum();
+                (ss / (n - 1) as f64).sqrt()
+            }
+            Stat::Quantile(q) => {
+                // The same interpolation as `Recorder::quantile()`.
+                let h = (n - 1) as f64 * q;
+                let lorank = h.floor() as u64;
+                let lo = value_at_rank(buckets, weights, lorank);
+                let hi = value_at_rank(buckets, weights, (lorank + 1).min(n - 1));
+                lo as f64 + (h - lorank as f64) * (hi as f64 - lo as f64)
+            }
+        }
+    }
+}
+
+fn value_at_rank(buckets: &[(u64, u64)], weights: &[u64], rank: u64) -> u64 {
+    let mut seen = 0;
+    for ((v, _), w) in buckets.iter().zip(weights) {
+        seen += w;
+        if seen > rank {
+            return *v;
+        }
+    }
+    buckets.last().map_or(0, |(v, _)| *v)
+}
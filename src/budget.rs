@@ -0,0 +1,197 @@
+use std::env;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::{get_iters, ClockFn};
+use crate::histogram::{Histogram, Recorder};
+use crate::workload::Workload;
+
+/// How long `--adaptive` keeps going if it hasn't converged and there's no `--duration`.
+const DEFAULT_ADAPTIVE_LIMIT: Duration = Duration::from_secs(60);
+
+/// The first batch of samples taken by a time-boxed or adaptive run, before we know how long a
+/// sample takes.
+const FIRST_BATCH: u64 = 1_000;
+
+/// The most samples taken between checks of the clock and of convergence.
+const MAX_BATCH: u64 = 1_000_000;
+
+/// z for a two-sided 95% confidence interval.
+const Z95: f64 = 1.959964;
+
+/// How many samples to take of each clock.
+#[derive(Clone, Copy, Debug)]
+pub enum Budget {
+    /// Exactly this many (`--iters=N`, the default).
+    Iters(u64),
+    /// As many as fit in this much time (`--duration=T`).
+    Duration(Duration),
+    /// Until the 95% confidence interval on the `quantile` is narrower than `width` times the
+    /// quantile's value (`--adaptive=pQ:WIDTH`), or `limit` runs out.
+    Adaptive { quantile: f64, width: f64, limit: Duration },
+}
+
+/// Parses `30s`, `500ms`, `2m` or `1.5s`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (numstr, scale) = if let Some(n) = s.strip_suffix("ms") {
+        (n, 0.001)
+    } else if let Some(n) = s.strip_suffix('s') {
+        (n, 1.0)
+    } else if let Some(n) = s.strip_suffix('m') {
+        (n, 60.0)
+    } else {
+        return Err(format!("duration {s:?} needs a unit: ms, s or m"));
+    };
+    let num = numstr.parse::<f64>().map_err(|e| format!("bad duration {s:?}: {e}"))?;
+    Duration::try_from_secs_f64(num * scale).map_err(|e| format!("bad duration {s:?}: {e}"))
+}
+
+/// Parses a percentile such as `p99.9` (or just `99.9`) into the quantile 0.999.
+pub fn parse_percentile(s: &str) -> Result<f64, String> {
+    let numstr = s.strip_prefix('p').unwrap_or(s);
+    let perc = numstr.parse::<f64>().map_err(|e| format!("bad percentile {s:?}: {e}"))?;
+    if !(0.0..=100.0).contains(&perc) {
+        return Err(format!("percentile {s:?} isn't between 0 and 100"));
+    }
+    Ok(perc / 100.0)
+}
+
+/// Returns the budget from `--duration=T` and/or `--adaptive=pQ:WIDTH`, or `--iters=N`.
+pub fn get_budget() -> Budget {
+    static BUDGET: OnceLock<Budget> = OnceLock::new();
+
+    *BUDGET.get_or_init(|| {
+        let mut duration = None;
+        let mut adaptive = None;
+        for arg in env::args() {
+            if let Some(durstr) = arg.strip_prefix("--duration=") {
+                duration = Some(parse_duration(durstr).unwrap_or_else(|e| panic!("{e}")));
+            } else if let Some(adstr) = arg.strip_prefix("--adaptive=") {
+                let Some((percstr, widthstr)) = adstr.split_once(':') else {
+                    panic!("--adaptive= takes pQ:WIDTH, e.g. --adaptive=p99:0.01, not {adstr:?}");
+                };
+                let quantile = parse_percentile(percstr).unwrap_or_else(|e| panic!("{e}"));
+                let Ok(width) = widthstr.parse::<f64>() else {
+                    panic!("--adaptive= width needs to be a number, not {widthstr:?}");
+                };
+                adaptive = Some((quantile, width));
+            }
+        }
+
+        match (adaptive, duration) {
+            (Some((quantile, width)), limit) => Budget::Adaptive { quantile, width, limit: limit.unwrap_or(DEFAULT_ADAPTIVE_LIMIT) },
+            (None, Some(d)) => Budget::Duration(d),
+            (None, None) => Budget::Iters(get_iters()),
+        }
+    })
+}
+
+impl Budget {
+    /// Returns true once the fixed count or the time has been used up.
+    pub fn spent(&self, nsamples: u64, elapsed: Duration) -> bool {
+        match *self {
+            Budget::Iters(iters) => nsamples >= iters,
+            Budget::Duration(limit) | Budget::Adaptive { limit, .. } => elapsed >= limit,
+        }
+    }
+
+    /// This budget shared out evenly between `n` measurements, so that together they cost about
+    /// as much as one measurement would. Each gets at least one sample.
+    pub fn split(&self, n: usize) -> Budget {
+        let n = n.max(1) as u32;
+        match *self {
+            Budget::Iters(iters) => Budget::Iters((iters / n as u64).max(1)),
+            Budget::Duration(limit) => Budget::Duration(limit / n),
+            Budget::Adaptive { quantile, width, limit } => Budget::Adaptive { quantile, width, limit: limit / n },
+        }
+    }
+
+    /// Returns true if this is an adaptive budget and the samples are precise enough.
+    pub fn converged(&self, rec: &Recorder) -> bool {
+        match *self {
+            Budget::Adaptive { quantile, width, .. } => quantile_ci_width(&rec.hist, quantile).is_some_and(|w| w <= width),
+            _ => false,
+        }
+    }
+
+    /// How many samples to take next, given how many we have and how long they took. Batches
+    /// double in size, but not past what we expect to fit in the time that's left.
+    fn next_batch(&self, nsamples: u64, elapsed: Duration, prevbatch: u64) -> u64 {
+        let limit = match *self {
+            Budget::Iters(iters) => return iters - nsamples,
+            Budget::Duration(limit) | Budget::Adaptive { limit, .. } => limit,
+        };
+        if nsamples == 0 {
+            return FIRST_BATCH;
+        }
+        let persample = elapsed.as_secs_f64() / nsamples as f64;
+        let remaining = limit.saturating_sub(elapsed).as_secs_f64();
+        let fits = (remaining / persample).ceil() as u64;
+        (prevbatch * 2).min(MAX_BATCH).min(fits).max(1)
+    }
+}
+
+/// Returns the width of the distribution-free 95% confidence interval on the given quantile,
+/// relative to the quantile's value, or None if there aren't enough samples for the interval's
+/// ends to be among them. The interval's ends are the order statistics at ranks
+/// n*q -/+ z*sqrt(n*q*(1-q)).
+pub fn quantile_ci_width(hist: &Histogram, quantile: f64) -> Option<f64> {
+    let n = hist.total() as f64;
+    let half = Z95 * (n * quantile * (1.0 - quantile)).sqrt();
+    let lo = (n * quantile - half).floor();
+    let hi = (n * quantile + half).ceil();
+    if lo < 0.0 || hi >= n {
+        return None;
+    }
+
+    let loval = hist.value_at_rank(lo as u64)?;
+    let midval = hist.value_at_rank(((n * quantile) as u64).min(hist.total() - 1))?;
+    let hival = hist.value_at_rank(hi as u64)?;
+
+    if midval == 0 {
+        return None;
+    }
+    Some((hival - loval) as f64 / midval as f64)
+}
+
+/// Takes samples of the clock into the recorder until the budget says to stop.
+pub fn take_samples(cf: &ClockFn, workload: &Workload, budget: &Budget, rec: &mut Recorder) {
+    take_samples_with(cf, budget, rec, |n, rec| (cf.func)(cf.clock, n, workload, rec));
+}
+
+/// Like `take_samples()`, but takes each batch of `n` samples by calling `run(n, rec)`, for callers
+/// that need to do something around every sample.
+pub fn take_samples_with(cf: &ClockFn, budget: &Budget, rec: &mut Recorder, mut run: impl FnMut(u64, &mut Recorder)) {
+    if let Budget::Iters(iters) = *budget {
+        run(iters, rec);
+        return;
+    }
+
+    let start = Instant::now();
+    let mut batch = 0;
+    loop {
+        batch = budget.next_batch(rec.count, start.elapsed(), batch);
+        run(batch, rec);
+
+        if budget.spent(rec.count, start.elapsed()) {
+            report_unconverged(cf, budget, rec);
+            break;
+        }
+        if budget.converged(rec) {
+            break;
+        }
+    }
+}
+
+/// Says so if an adaptive run ran out of time before getting precise enough.
+pub fn report_unconverged(cf: &ClockFn, budget: &Budget, rec: &Recorder) {
+    if let Budget::Adaptive { quantile, width, limit } = *budget
+        && !budget.converged(rec)
+    {
+        let got = match quantile_ci_width(&rec.hist, quantile) {
+            Some(w) => format!("{:.3}%", w * 100.0),
+            None => "unknown".to_string(),
+        };
+        println!("adaptive: {} didn't get the p{} CI width down to {:.3}% in {:?} (it's {got})", cf.label(), quantile * 100.0, width * 100.0, limit);
+    }
+}
@@ -0,0 +1,298 @@
+use std::env;
+use std::hint::spin_loop;
+use std::sync::OnceLock;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::budget::parse_duration;
+use crate::preemption::thread_context_switches;
+use crate::reference::{get_reference, Reference};
+use crate::ClockFn;
+
+const DEFAULT_ROUNDS: u32 = 5;
+const DEFAULT_ROUND_TIME: Duration = Duration::from_millis(1);
+
+/// Rounds further than this many (normal-scaled) MADs from the median round are dropped.
+const OUTLIER_MADS: f64 = 3.0;
+
+/// A calibration whose rounds have a relative standard deviation above this gets flagged.
+const VARIES_ABOVE: f64 = 0.001;
+
+/// A CPU-time clock whose on-CPU rate is further than this from wall time gets flagged.
+const SKEWED_ABOVE: f64 = 0.01;
+
+/// A spinning round (see `spins()`) that the thread got switched out during is taken again, up to
+/// this many times the number of rounds in all.
+const MAX_ATTEMPTS_PER_ROUND: u32 = 10;
+
+/// Settings from `--calibrate=ROUNDSxTIME`.
+#[derive(Clone, Copy, Debug)]
+pub struct CalibrationSettings {
+    pub rounds: u32,
+    pub round_time: Duration,
+    /// Whether `--calibrate=` was given, in which case every calibration gets reported, not just
+    /// the ones that vary.
+    pub explicit: bool,
+}
+
+/// Returns the `--calibrate=ROUNDSxTIME` settings, e.g. `--calibrate=10x5ms`, or the default of
+/// 5 rounds of 1ms.
+pub fn get_calibration_settings() -> CalibrationSettings {
+    static SETTINGS: OnceLock<CalibrationSettings> = OnceLock::new();
+
+    *SETTINGS.get_or_init(|| {
+        for arg in env::args() {
+            if let Some(calstr) = arg.strip_prefix("--calibrate=") {
+                let Some((roundsstr, timestr)) = calstr.split_once('x') else {
+                    panic!("--calibrate= takes ROUNDSxTIME, e.g. --calibrate=10x5ms, not {calstr:?}");
+                };
+                let rounds = match roundsstr.parse::<u32>() {
+                    Ok(r) if r >= 1 => r,
+                    _ => panic!("--calibrate= needs at least 1 round, not {roundsstr:?}"),
+                };
+                let round_time = parse_duration(timestr).unwrap_or_else(|e| panic!("{e}"));
+                return CalibrationSettings { rounds, round_time, explicit: true };
+            }
+        }
+        CalibrationSettings { rounds: DEFAULT_ROUNDS, round_time: DEFAULT_ROUND_TIME, explicit: false }
+    })
+}
+
+/// How long each round of calibration lasts, i.e. how long the calibrate functions sleep for.
+pub fn caltime() -> Duration {
+    get_calibration_settings().round_time
+}
+
+/// Whether calibration rounds spin rather than sleep: for clocks that count the thread's CPU time,
+/// which doesn't advance while it sleeps, and for every clock under `--reference=cycles`, whose
+/// counter doesn't either.
+pub fn spins(cputime: bool) -> bool {
+    cputime || get_reference() == Reference::Cycles
+}
+
+/// Waits out one calibration round, spinning if `spins()` says so. For a CPU-time clock that
+/// makes its calibration compare CPU time with wall time while the thread is on the CPU. That
+/// ought to come out at 1; if it doesn't, the kernel's CPU-time accounting is skewed, and if it
+/// jumps around between rounds, it's coarse.
+pub fn wait_round(cputime: bool) {
+    if spins(cputime) {
+        let start = Instant::now();
+        while start.elapsed() < caltime() {
+            spin_loop();
+        }
+    } else {
+        sleep(caltime());
+    }
+}
+
+/// Whether the clock counts CPU time rather than wall time.
+pub fn is_cputime(cf: &ClockFn) -> bool {
+    cf.clockname.contains("CPUTIME")
+}
+
+/// A clock's rate relative to the reference clock, combined from several rounds of its calibrate
+/// function.
+#[derive(Clone, Debug)]
+pub struct Calibration {
+    /// The totals of the kept rounds' (numer, denomer), i.e. the clock's ticks (or nanoseconds)
+    /// and the reference clock's nanoseconds over all of them.
+    pub numer: u64,
+    pub denomer: u64,
+    pub rounds: u32,
+    /// How many rounds were dropped as outliers.
+    pub dropped: u32,
+    /// The standard error of the ratio, relative to the ratio, or None with only one round kept.
+    pub relerr: Option<f64>,
+    /// The relative standard deviation of all the rounds' ratios, outliers included.
+    pub spread: f64,
+    /// Whether this is a CPU-time clock.
+    pub cputime: bool,
+    /// How many spinning rounds were taken again because the thread got switched out during
+    /// them, which stops CPU time and cycles but not wall time.
+    pub preempted: u32,
+    /// Whether the spinning rounds all ran without the thread being switched out. If it couldn't
+    /// get enough such rounds, the rounds it kept say more about how busy the CPU was than about
+    /// the clock.
+    pub on_cpu: bool,
+}
+
+impl Calibration {
+    pub fn ratio(&self) -> f64 {
+        self.numer as f64 / self.denomer as f64
+    }
+
+    /// The conversion from this clock's ticks to nanoseconds.
+    pub fn tick_scale(&self) -> TickScale {
+        TickScale::new(self.numer, self.denomer)
+    }
+
+    /// Pools another calibration of the same clock (from another thread) into this one, as if its
+    /// rounds had been this one's too. The standard error becomes the larger of the two, and the
+    /// spread the wider.
+    pub fn merge(&mut self, other: &Calibration) {
+        self.numer += other.numer;
+        self.denomer += other.denomer;
+        self.rounds += other.rounds;
+        self.dropped += other.dropped;
+        self.relerr = match (self.relerr, other.relerr) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        self.spread = self.spread.max(other.spread);
+        self.preempted += other.preempted;
+        self.on_cpu &= other.on_cpu;
+    }
+
+    /// Whether the rounds disagree with each other by more than a measurement this short
+    /// explains. Spinning rounds that the thread got switched out during would, so they don't
+    /// count.
+    pub fn varies(&self) -> bool {
+        self.on_cpu && self.spread > VARIES_ABOVE
+    }
+
+    /// Whether the thread got switched out during too many spinning rounds for the calibration
+    /// to say anything about the clock.
+    pub fn off_cpu(&self) -> bool {
+        !self.on_cpu
+    }
+
+    /// Whether this is a CPU-time clock that doesn't run at the same rate as wall time while the
+    /// thread is on the CPU, which can only be told from rounds it stayed on the CPU for.
+    pub fn skewed(&self) -> bool {
+        self.cputime && self.on_cpu && (self.ratio() - 1.0).abs() > SKEWED_ABOVE
+    }
+}
+
+/// Converts ticks to nanoseconds given that `numer` ticks took `denomer` nanoseconds, the way the
+/// Linux clocksource code does: ns = (ticks * mult) >> shift, where mult = (denomer << shift) /
+/// numer, rounded. The multiplication is done in u128 so that it can't overflow, and the result
+/// is rounded to the nearest nanosecond rather than truncated, so that durations don't pile up on
+/// every other bucket. As mult is rounded too, the result can be off the nearest nanosecond by up
+/// to ticks / 2^33 more, which only matters past billions of ticks.
+#[derive(Clone, Copy, Debug)]
+pub struct TickScale {
+    mult: u128,
+}
+
+impl TickScale {
+    const SHIFT: u32 = 32;
+
+    pub fn new(numer: u64, denomer: u64) -> TickScale {
+        assert!(numer > 0, "can't scale a clock that didn't tick during calibration");
+        let mult = (((denomer as u128) << Self::SHIFT) + numer as u128 / 2) / numer as u128;
+        TickScale { mult }
+    }
+
+    #[inline]
+    pub fn to_nanos(self, ticks: u64) -> u64 {
+        let ns = (ticks as u128 * self.mult + (1u128 << (Self::SHIFT - 1))) >> Self::SHIFT;
+        ns.min(u64::MAX as u128) as u64
+    }
+}
+
+/// Runs the clock's calibrate function for the configured number of rounds, drops rounds whose
+/// ratio is an outlier (more than `OUTLIER_MADS` scaled median absolute deviations from the
+/// median), and combines the rest. Spinning rounds that the thread got switched out during are
+/// taken again (see `MAX_ATTEMPTS_PER_ROUND`), and if that doesn't get enough of them the
+/// switched-out ones make up the numbers.
+pub fn calibrate(cf: &ClockFn) -> Calibration {
+    let settings = get_calibration_settings();
+    let cputime = is_cputime(cf);
+    let mut pairs: Vec<(u64, u64)> = Vec::new();
+    let mut switchedout: Vec<(u64, u64)> = Vec::new();
+    for _ in 0..settings.rounds * MAX_ATTEMPTS_PER_ROUND {
+        if pairs.len() == settings.rounds as usize {
+            break;
+        }
+        let switches1 = thread_context_switches();
+        let pair = (cf.calibrate)(cf.clock);
+        if spins(cputime) && thread_context_switches() != switches1 {
+            switchedout.push(pair);
+        } else {
+            pairs.push(pair);
+        }
+    }
+    let preempted = switchedout.len() as u32;
+    let on_cpu = pairs.len() == settings.rounds as usize;
+    pairs.extend(switchedout.into_iter().take(settings.rounds as usize - pairs.len()));
+    let ratios: Vec<f64> = pairs.iter().map(|&(n, d)| n as f64 / d as f64).collect();
+
+    let mid = median(&ratios);
+    let mad = 1.4826 * median(&ratios.iter().map(|r| (r - mid).abs()).collect::<Vec<f64>>());
+    let keep = |r: f64| mad == 0.0 || (r - mid).abs() <= OUTLIER_MADS * mad;
+
+    let kept: Vec<usize> = (0..pairs.len()).filter(|&i| keep(ratios[i])).collect();
+    let numer = kept.iter().map(|&i| pairs[i].0).sum();
+    let denomer = kept.iter().map(|&i| pairs[i].1).sum();
+
+    let keptratios: Vec<f64> = kept.iter().map(|&i| ratios[i]).collect();
+    let relerr = (keptratios.len() > 1).then(|| stddev(&keptratios) / (keptratios.len() as f64).sqrt() / mid);
+
+    Calibration { numer, denomer, rounds: settings.rounds, dropped: settings.rounds - kept.len() as u32, relerr, spread: stddev(&ratios) / mid, cputime, preempted, on_cpu }
+}
+
+fn median(xs: &[f64]) -> f64 {
+    let mut sorted = xs.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) { (sorted[mid - 1] + sorted[mid]) / 2.0 } else { sorted[mid] }
+}
+
+fn stddev(xs: &[f64]) -> f64 {
+    if xs.len() < 2 {
+        return 0.0;
+    }
+    let mean = xs.iter().sum::<f64>() / xs.len() as f64;
+    (xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (xs.len() - 1) as f64).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How far the conversion is from the exact number of nanoseconds, and how far the rounding of
+    /// the result and of mult allow it to be.
+    fn error_and_bound(scale: TickScale, ticks: u64, numer: u64, denomer: u64) -> (f64, f64) {
+        let want = ticks as f64 * denomer as f64 / numer as f64;
+        ((scale.to_nanos(ticks) as f64 - want).abs(), 0.5 + ticks as f64 / (1u64 << 33) as f64 + want * 1e-15)
+    }
+
+    #[test]
+    fn equal_rates_convert_exactly() {
+        let scale = TickScale::new(1_000, 1_000);
+        for ticks in [0, 1, 2, 1_000_000_007, u64::MAX / 2, u64::MAX] {
+            assert_eq!(scale.to_nanos(ticks), ticks);
+        }
+    }
+
+    #[test]
+    fn rounds_to_the_nearest_nanosecond() {
+        let scale = TickScale::new(3, 1);
+        let got: Vec<u64> = (0..7).map(|t| scale.to_nanos(t)).collect();
+        assert_eq!(got, vec![0, 0, 1, 1, 1, 2, 2]);
+        assert_eq!(scale.to_nanos(300), 100);
+        assert_eq!(TickScale::new(1, 2).to_nanos(7), 14);
+    }
+
+    #[test]
+    fn stays_within_the_bound_of_the_exact_conversion() {
+        for (numer, denomer) in [(2_099_549, 1_000_000), (24, 1_000), (1_000_000, 41_666_667), (3, 1)] {
+            let scale = TickScale::new(numer, denomer);
+            for ticks in (0..200_000).chain((0..1_000).map(|i| i * 1_000_003)).chain([3_000_000_000_000, 1 << 50]) {
+                let (err, bound) = error_and_bound(scale, ticks, numer, denomer);
+                assert!(err <= bound, "{ticks} ticks at {numer}/{denomer} are off by {err}ns, more than {bound}ns");
+            }
+        }
+    }
+
+    #[test]
+    fn saturates_instead_of_overflowing() {
+        assert_eq!(TickScale::new(1, 2).to_nanos(u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    #[should_panic]
+    fn refuses_a_clock_that_did_not_tick() {
+        TickScale::new(0, 1_000);
+    }
+}
@@ -0,0 +1,389 @@
+use crate::calibration::TickScale;
+
+/// Values below `2 * SUB_BUCKETS` get a bucket each; above that every power of two is split into
+/// `SUB_BUCKETS` equal buckets, so a bucket is never wider than 1/1024 (about 0.1%) of the values
+/// in it.
+const SUB_BUCKET_BITS: u32 = 10;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+/// The number of buckets needed to cover every u64.
+const MAX_BUCKETS: usize = ((64 - SUB_BUCKET_BITS) as usize + 1) * SUB_BUCKETS as usize;
+
+/// A log-linear histogram (in the style of HdrHistogram) of u64 values, whose memory use depends
+/// only on the largest value recorded and not on how many values were recorded.
+#[derive(Clone, Debug, Default)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+fn bucket_index(v: u64) -> usize {
+    if v < 2 * SUB_BUCKETS {
+        return v as usize;
+    }
+    let topbit = 63 - v.leading_zeros();
+    let shift = topbit - SUB_BUCKET_BITS;
+    ((shift as u64 + 1) * SUB_BUCKETS + ((v >> shift) - SUB_BUCKETS)) as usize
+}
+
+/// The smallest value that lands in bucket `i`, and the bucket's width.
+fn bucket_range(i: usize) -> (u64, u64) {
+    let i = i as u64;
+    if i < 2 * SUB_BUCKETS {
+        return (i, 1);
+    }
+    let shift = i / SUB_BUCKETS - 1;
+    let sub = i % SUB_BUCKETS + SUB_BUCKETS;
+    (sub << shift, 1 << shift)
+}
+
+/// The value that stands for everything in bucket `i`: the middle of the bucket.
+fn bucket_value(i: usize) -> u64 {
+    let (low, width) = bucket_range(i);
+    low + (width - 1) / 2
+}
+
+impl Histogram {
+    #[inline]
+    pub fn record(&mut self, v: u64) {
+        self.record_n(v, 1);
+    }
+
+    #[inline]
+    pub fn record_n(&mut self, v: u64, n: u64) {
+        let i = bucket_index(v);
+        if i >= self.counts.len() {
+            self.counts.resize(i + 1, 0);
+        }
+        self.counts[i] += n;
+        self.total += n;
+    }
+
+    /// Allocates and touches every bucket there could ever be, so that recording never allocates
+    /// or page faults.
+    pub fn prefault(&mut self) {
+        self.counts.resize(MAX_BUCKETS, 0);
+        std::hint::black_box(&mut self.counts);
+    }
+
+    /// Adds every sample of `other` to this histogram.
+    pub fn merge(&mut self, other: &Histogram) {
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (c, o) in self.counts.iter_mut().zip(&other.counts) {
+            *c += o;
+        }
+        self.total += other.total;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Iterates over (value, count) for every non-empty bucket, in increasing order of value.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.counts.iter().enumerate().filter(|(_, c)| **c > 0).map(|(i, c)| (bucket_value(i), *c))
+    }
+
+    /// Returns the value of the sample at 0-based `rank` in sorted order.
+    pub fn value_at_rank(&self, rank: u64) -> Option<u64> {
+        let mut seen = 0;
+        for (v, c) in self.iter() {
+            seen += c;
+            if seen > rank {
+                return Some(v);
+            }
+        }
+        None
+    }
+}
+
+/// Everything we keep about a stream of samples: exact count, min, max, mean and variance
+/// (Welford's algorithm), plus a histogram for everything that needs the shape of the
+/// distribution. Uses constant memory however many samples go through it, including the optional
+/// series of samples in order, which stops growing at its limit.
+#[derive(Clone, Debug, Default)]
+pub struct Recorder {
+    /// If set, samples are in clock ticks and get converted to nanoseconds as they are recorded.
+    scale: Option<TickScale>,
+    /// If set, the unconverted ticks also get recorded here.
+    pub ticks: Option<Box<Recorder>>,
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    /// The most recently recorded value.
+    pub last: u64,
+    /// How many samples the clock read as zero or negative, which aren't recorded.
+    pub skipped: u64,
+    /// Sum of squared differences from the mean, as per Welford.
+    m2: f64,
+    pub hist: Histogram,
+    /// If set, the first `series_limit` samples in the order they were taken.
+    pub series: Option<Vec<u64>>,
+    series_limit: usize,
+}
+
+impl Recorder {
+    pub fn new(scale: Option<TickScale>) -> Recorder {
+        Recorder { scale, min: u64::MAX, ..Default::default() }
+    }
+
+    /// Also keeps the raw ticks of a scaled clock, in a recorder of their own.
+    pub fn keep_ticks(&mut self) {
+        if self.scale.is_some() {
+            self.ticks = Some(Box::new(Recorder::new(None)));
+        }
+    }
+
+    /// Also keeps the first `limit` samples in order, for looking for patterns over time.
+    pub fn keep_series(&mut self, limit: usize) {
+        self.series = Some(Vec::with_capacity(limit));
+        self.series_limit = limit;
+    }
+
+    /// Counts a sample that the clock read as zero or negative, instead of recording it.
+    #[inline]
+    pub fn skip(&mut self) {
+        self.skipped += 1;
+    }
+
+    #[inline]
+    pub fn record(&mut self, dur: u64) {
+        let v = match self.scale {
+            Some(scale) => {
+                if let Some(ticks) = &mut self.ticks {
+                    ticks.record(dur);
+                }
+                scale.to_nanos(dur)
+            }
+            None => dur,
+        };
+
+        self.last = v;
+        self.count += 1;
+        self.min = self.min.min(v);
+        self.max = self.max.max(v);
+        let delta = v as f64 - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (v as f64 - self.mean);
+        self.hist.record(v);
+        if let Some(series) = &mut self.series
+            && series.len() < self.series_limit
+        {
+            series.push(v);
+        }
+    }
+
+    /// Adds every sample of `other` to this recorder, as if they had been recorded here, combining
+    /// the means and variances with Chan et al.'s formula. The series, being in the order the
+    /// samples were taken, can't be merged, so it's dropped; look for periods in each recorder's
+    /// series before merging them.
+    pub fn merge(&mut self, other: &Recorder) {
+        self.skipped += other.skipped;
+        if other.count == 0 {
+            return;
+        }
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        self.m2 += other.m2 + delta * delta * self.count as f64 * other.count as f64 / count as f64;
+        self.mean += delta * other.count as f64 / count as f64;
+        self.count = count;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.last = other.last;
+        self.hist.merge(&other.hist);
+        if let (Some(ticks), Some(otherticks)) = (&mut self.ticks, &other.ticks) {
+            ticks.merge(otherticks);
+        }
+        self.series = None;
+    }
+
+    /// Returns the `q` quantile (0 <= q <= 1) of the samples, or 0 if there are none.
+    ///
+    /// This uses linear interpolation between the closest ranks, i.e. Hyndman and Fan's
+    /// definition 7, which is also what R and numpy do by default: with the n samples sorted into
+    /// x[0] <= ... <= x[n-1] and h = (n-1)*q, the quantile is x[floor(h)] + (h - floor(h)) *
+    /// (x[floor(h)+1] - x[floor(h)]). So p0 is the minimum, p100 the maximum, and p50 of an even
+    /// number of samples is the mean of the middle two. x[0] and x[n-1] are the exact min and max;
+    /// the others come from the histogram, so they are exact below 2048ns and within 0.1% above.
+    pub fn quantile(&self, q: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let h = (self.count - 1) as f64 * q.clamp(0.0, 1.0);
+        let lorank = h.floor() as u64;
+        let hirank = (lorank + 1).min(self.count - 1);
+        let lo = self.value_at_rank(lorank);
+        let hi = self.value_at_rank(hirank);
+        (lo as f64 + (h - lorank as f64) * (hi as f64 - lo as f64)).round() as u64
+    }
+
+    /// The value of the sample at 0-based `rank` in sorted order, using the exact min and max for
+    /// the first and last ranks.
+    fn value_at_rank(&self, rank: u64) -> u64 {
+        if rank == 0 {
+            return self.min;
+        }
+        if rank + 1 >= self.count {
+            return self.max;
+        }
+        self.hist.value_at_rank(rank).unwrap_or(self.max).clamp(self.min, self.max)
+    }
+
+    /// Iterates over (value, count) like `Histogram::iter()`, but with bucket values clamped to
+    /// the exact min and max, so that a bucket holding a single sample isn't off by half its width.
+    fn values(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.hist.iter().map(|(v, c)| (v.clamp(self.min, self.max), c))
+    }
+
+    /// The most common value, or the lowest of them if there's a tie.
+    pub fn mode(&self) -> u64 {
+        let mut best = (0, 0);
+        for (v, c) in self.values() {
+            if c > best.1 {
+                best = (v, c);
+            }
+        }
+        best.0
+    }
+
+    /// The median absolute deviation: the median distance of the samples from their median.
+    /// Unscaled, so for normally distributed samples it is about 0.6745 times the stddev.
+    pub fn mad(&self) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let median = self.quantile(0.5);
+        let mut devs: Vec<(u64, u64)> = self.values().map(|(v, c)| (v.abs_diff(median), c)).collect();
+        devs.sort_unstable();
+        let mut seen = 0;
+        for (d, c) in devs {
+            seen += c;
+            if seen > (self.count - 1) / 2 {
+                return d;
+            }
+        }
+        0
+    }
+
+    /// The mean of the samples with the lowest and highest `frac` of them left out.
+    pub fn trimmed_mean(&self, frac: f64) -> f64 {
+        let cut = (self.count as f64 * frac).floor() as u64;
+        let (sum, n) = self.sum_between_ranks(cut, self.count.saturating_sub(cut));
+        if n == 0 { self.mean } else { sum / n as f64 }
+    }
+
+    /// The mean of the samples with the lowest and highest `frac` of them replaced by the lowest
+    /// and highest of the ones that are left.
+    pub fn winsorized_mean(&self, frac: f64) -> f64 {
+        let cut = (self.count as f64 * frac).floor() as u64;
+        let (sum, n) = self.sum_between_ranks(cut, self.count.saturating_sub(cut));
+        if n == 0 {
+            return self.mean;
+        }
+        let lo = self.value_at_rank(cut) as f64;
+        let hi = self.value_at_rank(self.count - cut - 1) as f64;
+        (sum + cut as f64 * (lo + hi)) / self.count as f64
+    }
+
+    /// The sum and number of the samples with 0-based ranks in `lo..hi`.
+    fn sum_between_ranks(&self, lo: u64, hi: u64) -> (f64, u64) {
+        let mut sum = 0.0;
+        let mut n = 0;
+        let mut rank = 0;
+        for (v, c) in self.values() {
+            let take = (rank + c).min(hi).saturating_sub(rank.max(lo));
+            sum += v as f64 * take as f64;
+            n += take;
+            rank += c;
+        }
+        (sum, n)
+    }
+
+    /// Sample standard deviation.
+    pub fn stddev(&self) -> f64 {
+        if self.count < 2 {
+            return 0f64;
+        }
+        (self.m2 / (self.count - 1) as f64).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorder_of(values: &[u64]) -> Recorder {
+        let mut rec = Recorder::new(None);
+        for &v in values {
+            rec.record(v);
+        }
+        rec
+    }
+
+    #[test]
+    fn buckets_hold_their_values_to_within_a_thousandth() {
+        for v in [0, 1, 2047, 2048, 2049, 4095, 4096, 123_456_789, u64::MAX / 3, u64::MAX] {
+            let i = bucket_index(v);
+            assert!(i < MAX_BUCKETS);
+            let (low, width) = bucket_range(i);
+            assert!(low <= v && v - low < width, "{v} isn't in bucket {i} ({low} + {width})");
+            assert!(width == 1 || width <= v / SUB_BUCKETS, "bucket {i} is {width} wide for {v}");
+        }
+    }
+
+    #[test]
+    fn quantiles_interpolate_like_numpy() {
+        let rec = recorder_of(&[10, 20, 30, 40, 50, 60, 70, 80, 90, 100]);
+        assert_eq!(rec.quantile(0.0), 10);
+        assert_eq!(rec.quantile(0.5), 55);
+        assert_eq!(rec.quantile(0.9), 91);
+        assert_eq!(rec.quantile(0.25), 33);
+        assert_eq!(rec.quantile(1.0), 100);
+        assert_eq!(Recorder::new(None).quantile(0.5), 0);
+    }
+
+    #[test]
+    fn quantiles_of_large_values_are_within_a_thousandth() {
+        let rec = recorder_of(&[1_000_000, 2_000_000, 3_000_000]);
+        let median = rec.quantile(0.5);
+        assert!(median.abs_diff(2_000_000) <= 2_000, "{median}");
+        assert_eq!(rec.quantile(0.0), 1_000_000);
+        assert_eq!(rec.quantile(1.0), 3_000_000);
+    }
+
+    #[test]
+    fn mode_mad_and_robust_means() {
+        let rec = recorder_of(&[1, 2, 2, 3]);
+        assert_eq!(rec.mode(), 2);
+
+        let rec = recorder_of(&[1, 2, 3, 4, 100]);
+        assert_eq!(rec.mad(), 1);
+
+        let rec = recorder_of(&[1, 2, 3, 10, 1000]);
+        assert_eq!(rec.trimmed_mean(0.2), 5.0);
+        assert_eq!(rec.winsorized_mean(0.2), 5.4);
+        assert_eq!(rec.trimmed_mean(0.0), rec.mean);
+    }
+
+    #[test]
+    fn merging_is_the_same_as_recording_everything_in_one() {
+        let all: Vec<u64> = (1..=10).collect();
+        let whole = recorder_of(&all);
+        let mut merged = recorder_of(&all[..3]);
+        merged.skip();
+        let mut other = recorder_of(&all[3..]);
+        other.skip();
+        merged.merge(&other);
+
+        assert_eq!(merged.count, 10);
+        assert_eq!(merged.skipped, 2);
+        assert_eq!((merged.min, merged.max), (1, 10));
+        assert!((merged.mean - 5.5).abs() < 1e-12);
+        assert!((merged.stddev() - whole.stddev()).abs() < 1e-12);
+        assert!((whole.stddev() - (55.0f64 / 6.0).sqrt()).abs() < 1e-12);
+        assert_eq!(merged.quantile(0.5), whole.quantile(0.5));
+    }
+}
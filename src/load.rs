@@ -0,0 +1,193 @@
+use std::hint::black_box;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+/// Size of the buffer the `mem` load streams through: 64 MiB, so it misses in every cache.
+const MEM_STREAM_BYTES: usize = 64 * 1024 * 1024;
+
+/// How much memory each round of the `pagefault` load maps and faults in.
+const PAGEFAULT_BYTES: usize = 4 * 1024 * 1024;
+
+/// How many rounds of work a load does between looking at its stop flag.
+const CHECK_EVERY: u64 = 1024;
+
+/// Something to keep a CPU busy with while clocks are being measured elsewhere.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadKind {
+    /// Nothing; the CPU is left idle.
+    Idle,
+    /// A dependent integer multiply/add loop.
+    Int,
+    /// 256-bit AVX floating point multiply/adds where the CPU has AVX, plain floating point
+    /// elsewhere.
+    Avx,
+    /// Reading and writing sequentially through a buffer much larger than the caches.
+    Mem,
+    /// Back-to-back `getpid` system calls.
+    Syscall,
+    /// Back-to-back reads of the time of day, which go through the same vDSO data (and seqlock)
+    /// as the clocks being measured.
+    TimeOfDay,
+    /// Mapping memory, touching every page of it, and unmapping it again.
+    PageFault,
+}
+
+impl LoadKind {
+    pub const ALL: [LoadKind; 7] = [LoadKind::Idle, LoadKind::Int, LoadKind::Avx, LoadKind::Mem, LoadKind::Syscall, LoadKind::TimeOfDay, LoadKind::PageFault];
+
+    /// The loads `--smt` runs on the sibling unless told otherwise.
+    pub const SMT_DEFAULT: [LoadKind; 4] = [LoadKind::Idle, LoadKind::Int, LoadKind::Avx, LoadKind::Mem];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LoadKind::Idle => "idle",
+            LoadKind::Int => "int",
+            LoadKind::Avx => "avx",
+            LoadKind::Mem => "mem",
+            LoadKind::Syscall => "syscall",
+            LoadKind::TimeOfDay => "timeofday",
+            LoadKind::PageFault => "pagefault",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<LoadKind, String> {
+        if s == "cpu" {
+            return Ok(LoadKind::Int);
+        }
+        LoadKind::ALL.into_iter().find(|k| k.name() == s).ok_or_else(|| {
+            let names: Vec<&str> = LoadKind::ALL.iter().map(|k| k.name()).collect();
+            format!("unknown load {s:?}, expected one of: {}", names.join(", "))
+        })
+    }
+}
+
+/// Parses a comma-separated list of loads, e.g. `idle,int,mem`.
+pub fn parse_load_list(s: &str) -> Result<Vec<LoadKind>, String> {
+    s.split(',').filter(|p| !p.is_empty()).map(LoadKind::parse).collect()
+}
+
+/// Runs the load on the calling thread until `stop` is set.
+pub fn run_load(kind: LoadKind, stop: &AtomicBool) {
+    match kind {
+        LoadKind::Idle => {}
+        LoadKind::Int => {
+            let mut acc: u64 = 1;
+            while !stop.load(Ordering::Relaxed) {
+                for i in 0..CHECK_EVERY {
+                    acc = black_box(acc.wrapping_mul(6364136223846793005).wrapping_add(i));
+                }
+            }
+        }
+        LoadKind::Avx => {
+            while !stop.load(Ordering::Relaxed) {
+                black_box(float_round());
+            }
+        }
+        LoadKind::Mem => {
+            let mut buf: Vec<u64> = vec![1; MEM_STREAM_BYTES / size_of::<u64>()];
+            while !stop.load(Ordering::Relaxed) {
+                let mut sum: u64 = 0;
+                for x in buf.iter_mut() {
+                    sum = sum.wrapping_add(*x);
+                    *x = sum;
+                }
+                black_box(sum);
+            }
+        }
+        LoadKind::Syscall => {
+            while !stop.load(Ordering::Relaxed) {
+                for _ in 0..CHECK_EVERY {
+                    black_box(crate::workload::getpid());
+                }
+            }
+        }
+        LoadKind::TimeOfDay => {
+            while !stop.load(Ordering::Relaxed) {
+                for _ in 0..CHECK_EVERY {
+                    black_box(SystemTime::now());
+                }
+            }
+        }
+        LoadKind::PageFault => {
+            while !stop.load(Ordering::Relaxed) {
+                fault_pages();
+            }
+        }
+    }
+}
+
+/// Maps `PAGEFAULT_BYTES` of fresh memory, writes to every page so that each one faults in, and
+/// unmaps it again. This goes straight to mmap rather than through the allocator, which might
+/// otherwise hang on to the memory and stop faulting.
+#[cfg(unix)]
+fn fault_pages() {
+    use crate::plat_unixes::libc;
+
+    let pagesize = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let p = unsafe { libc::mmap(std::ptr::null_mut(), PAGEFAULT_BYTES, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0) };
+    assert!(p != libc::MAP_FAILED, "mmap: {}", std::io::Error::last_os_error());
+
+    let bytes = p as *mut u8;
+    for off in (0..PAGEFAULT_BYTES).step_by(pagesize) {
+        unsafe { bytes.add(off).write_volatile(1) };
+    }
+
+    let retval = unsafe { libc::munmap(p, PAGEFAULT_BYTES) };
+    assert_eq!(retval, 0, "munmap: {}", std::io::Error::last_os_error());
+}
+
+#[cfg(not(unix))]
+fn fault_pages() {
+    let mut v: Vec<u8> = Vec::with_capacity(PAGEFAULT_BYTES);
+    for off in (0..PAGEFAULT_BYTES).step_by(4096) {
+        unsafe { v.as_mut_ptr().add(off).write_volatile(1) };
+    }
+    black_box(v);
+}
+
+#[cfg(target_arch = "x86_64")]
+fn float_round() -> f32 {
+    if is_x86_feature_detected!("avx") {
+        unsafe { avx_round() }
+    } else {
+        scalar_float_round() as f32
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn float_round() -> f32 {
+    scalar_float_round() as f32
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx")]
+fn avx_round() -> f32 {
+    use core::arch::x86_64::*;
+
+    let mul = _mm256_set1_ps(0.999_999);
+    let add = _mm256_set1_ps(0.000_001);
+    // Eight independent accumulators so that the vector units stay full.
+    let mut acc = [_mm256_set1_ps(1.0); 8];
+    for _ in 0..CHECK_EVERY {
+        for a in acc.iter_mut() {
+            *a = _mm256_add_ps(_mm256_mul_ps(*a, mul), add);
+        }
+    }
+    let mut out = [0f32; 8];
+    let mut sum = _mm256_setzero_ps();
+    for a in acc {
+        sum = _mm256_add_ps(sum, a);
+    }
+    unsafe { _mm256_storeu_ps(out.as_mut_ptr(), sum) };
+    out.iter().sum()
+}
+
+fn scalar_float_round() -> f64 {
+    let mut acc = [1f64; 8];
+    for _ in 0..CHECK_EVERY {
+        for a in acc.iter_mut() {
+            *a = black_box(*a * 0.999_999 + 0.000_001);
+        }
+    }
+    acc.iter().sum()
+}
@@ -0,0 +1,143 @@
+use std::env;
+use std::fs::File;
+use std::io;
+use std::sync::OnceLock;
+
+const DEFAULT_RT_PRIORITY: i32 = 50;
+
+#[derive(Clone, Copy, Debug)]
+pub enum RtPolicy {
+    Fifo,
+    RoundRobin,
+}
+
+/// Settings for `--lownoise`, which tries to keep the scheduler, page faults and deep C-states out
+/// of the measurements.
+#[derive(Clone, Copy, Debug)]
+pub struct LowNoise {
+    pub policy: RtPolicy,
+    pub priority: i32,
+}
+
+/// Returns the `--lownoise`, `--lownoise=fifo:PRIO` or `--lownoise=rr:PRIO` settings, if any.
+///
+/// Be careful with this on a machine that has fewer CPUs than measurement threads: real-time
+/// threads that never block can starve everything else, and only the kernel's RT throttling
+/// (`/proc/sys/kernel/sched_rt_runtime_us`) keeps the box responsive.
+pub fn get_lownoise() -> Option<LowNoise> {
+    static LOWNOISE: OnceLock<Option<LowNoise>> = OnceLock::new();
+
+    *LOWNOISE.get_or_init(|| {
+        for arg in env::args() {
+            if arg == "--lownoise" {
+                return Some(LowNoise { policy: RtPolicy::Fifo, priority: DEFAULT_RT_PRIORITY });
+            }
+            if let Some(lnstr) = arg.strip_prefix("--lownoise=") {
+                let (policystr, priostr) = lnstr.split_once(':').unwrap_or((lnstr, ""));
+                let policy = match policystr {
+                    "fifo" => RtPolicy::Fifo,
+                    "rr" => RtPolicy::RoundRobin,
+                    _ => panic!("--lownoise= takes fifo:PRIO or rr:PRIO, not {lnstr:?}"),
+                };
+                let priority = if priostr.is_empty() {
+                    DEFAULT_RT_PRIORITY
+                } else if let Ok(p) = priostr.parse::<i32>() {
+                    p
+                } else {
+                    panic!("--lownoise= priority needs to be a number, not {priostr:?}");
+                };
+                return Some(LowNoise { policy, priority });
+            }
+        }
+        None
+    })
+}
+
+impl std::fmt::Display for LowNoise {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.policy {
+            RtPolicy::Fifo => write!(f, "SCHED_FIFO:{}", self.priority),
+            RtPolicy::RoundRobin => write!(f, "SCHED_RR:{}", self.priority),
+        }
+    }
+}
+
+/// Does the process-wide parts of `--lownoise`: locks all current and future memory, and asks for
+/// zero CPU wakeup latency. Prints what worked and what didn't. The returned file has to be kept
+/// open for as long as the low latency request should stay in effect.
+pub fn setup_process() -> Option<File> {
+    match lock_memory() {
+        Ok(()) => println!("lownoise: mlockall: ok"),
+        Err(e) => println!("lownoise: mlockall: FAILED ({e})"),
+    }
+
+    match hold_cpu_dma_latency() {
+        Ok(f) => {
+            println!("lownoise: cpu_dma_latency=0: ok");
+            Some(f)
+        }
+        Err(e) => {
+            println!("lownoise: cpu_dma_latency=0: FAILED ({e})");
+            None
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn lock_memory() -> io::Result<()> {
+    use crate::plat_unixes::libc;
+
+    let retval = unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) };
+    if retval == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lock_memory() -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "only implemented on Linux"))
+}
+
+/// The kernel keeps CPUs out of deep C-states for as long as some process holds
+/// `/dev/cpu_dma_latency` open with a 0 written to it.
+#[cfg(target_os = "linux")]
+fn hold_cpu_dma_latency() -> io::Result<File> {
+    use std::io::Write;
+
+    let mut f = File::options().write(true).open("/dev/cpu_dma_latency")?;
+    f.write_all(&0i32.to_ne_bytes())?;
+    Ok(f)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn hold_cpu_dma_latency() -> io::Result<File> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "only implemented on Linux"))
+}
+
+/// Switches the calling thread to the real-time scheduling policy and priority.
+#[cfg(unix)]
+pub fn enter_realtime(ln: &LowNoise) -> io::Result<()> {
+    use crate::plat_unixes::libc;
+
+    let policy = match ln.policy {
+        RtPolicy::Fifo => libc::SCHED_FIFO,
+        RtPolicy::RoundRobin => libc::SCHED_RR,
+    };
+    let mut param: libc::sched_param = unsafe { std::mem::zeroed() };
+    param.sched_priority = ln.priority;
+
+    // pthread_setschedparam returns the error number rather than setting errno.
+    let retval = unsafe { libc::pthread_setschedparam(libc::pthread_self(), policy, &param) };
+    if retval == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(retval))
+    }
+}
+
+#[cfg(not(unix))]
+pub fn enter_realtime(_ln: &LowNoise) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "only implemented on unixes"))
+}
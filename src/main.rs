@@ -1,56 +1,69 @@
 #![feature(rustc_private)]
 
 use std::time::Instant;
-use rustc_hash::FxHashMap;
 
 const DEFAULT_ITERS: u64 = 100_000;
 
-const CALTIME_NANOS: u64 = 1_000_000;
-const D: Duration = Duration::from_nanos(CALTIME_NANOS);
-
-#[inline(never)]
-pub fn dummy_func() -> i64 {
-    // When I make this code a little faster/simpler then cputime on Macos starts telling me
-    // that it took 0 nanoseconds. 🤔
-    let mut a = Arc::new(0);
-    for i in 0..30 {
-        for j in 0..29 {
-            *Arc::make_mut(&mut a) ^= black_box(i * j);
-        }
-    }
-
-    *a
-}
+const JUMPTIME_NANOS: u64 = 1_000_000;
+const D: Duration = Duration::from_nanos(JUMPTIME_NANOS);
+
+mod workload;
+use workload::{Workload, get_workload};
+mod sweep;
+mod affinity;
+mod lownoise;
+mod percpu;
+mod load;
+mod smt;
+mod stress;
+mod schedule;
+mod budget;
+mod histogram;
+mod bootstrap;
+mod significance;
+mod calibration;
+mod reference;
+mod ratematrix;
+mod overhead;
+mod preemption;
+mod outliers;
+mod periodicity;
+mod modes;
+mod aggregate;
+use calibration::Calibration;
+use histogram::Recorder;
+use schedule::Schedule;
 
-use std::sync::Arc;
 use std::hint::black_box;
-fn instant(_clock: Option<ClockType>, iters: u64) -> Vec<u64> {
-    let mut durations = Vec::with_capacity(iters as usize);
-
+fn instant(_clock: Option<ClockType>, iters: u64, workload: &Workload, rec: &mut Recorder) {
     let mut i = 0;
     while i < iters {
         let inst = Instant::now();
 
-        black_box(dummy_func());
+        black_box(workload.run());
 
         let d = inst.elapsed();
-        assert!(d.as_nanos() > 0);
-        durations.push(d.as_nanos() as u64);
+        if d.as_nanos() > 0 {
+            rec.record(d.as_nanos() as u64);
+        } else {
+            rec.skip();
+        }
 
         i += 1;
     }
+}
 
-    durations
+/// Reads Instant, as nanoseconds since `run_epoch()`.
+fn instant_now(_clock: Option<ClockType>) -> u64 {
+    run_epoch().elapsed().as_nanos() as u64
 }
 
 #[cfg(windows)]
 pub mod plat_windows {
     use windows_sys::Win32::System::Performance::QueryPerformanceCounter;
-    use crate::{black_box, dummy_func, Instant, sleep, ClockType, DEFAULT_ITERS, D};
+    use crate::{black_box, calibration, reference, Recorder, Workload, ClockType};
     
-    pub fn qpc(_clock: Option<ClockType>) -> Vec<u64> {
-        let iters = get_iters();
-        let mut res = Vec::with_capacity(iters);
+    pub fn qpc(_clock: Option<ClockType>, iters: u64, workload: &Workload, rec: &mut Recorder) {
         let mut i = 0;
 
         while i < iters {
@@ -58,25 +71,33 @@ pub mod plat_windows {
 	    let mut stop: i64 = 0;
 	    let start_result = unsafe { QueryPerformanceCounter(&mut start) };
 
-            black_box(dummy_func());
+            black_box(workload.run());
 
 	    let stop_result = unsafe { QueryPerformanceCounter(&mut stop) };
 
 	    assert!(start_result != 0);
 	    assert!(stop_result != 0);
-            assert!(stop > start);
-            let ticks = stop as u64 - start as u64;
-
-            res.push(ticks);
+            if stop > start {
+                let ticks = stop as u64 - start as u64;
+                rec.record(ticks);
+            } else {
+                rec.skip();
+            }
 
             i += 1;
         }
+    }
 
-	res
+    /// Reads qpc, in ticks.
+    pub fn qpc_now(_clock: Option<ClockType>) -> u64 {
+        let mut now: i64 = 0;
+        let result = unsafe { QueryPerformanceCounter(&mut now) };
+        assert!(result != 0);
+        now as u64
     }
 
-    /// Returns the number of qpc ticks per nanosecond, in (numer, denomer) format.
-    /// Sleeps for about a millisecond in order to calibrate.
+    /// Returns the number of qpc ticks per reference nanosecond, in (numer, denomer) format.
+    /// Waits for one calibration round (see `calibration::wait_round()`).
     pub fn qpc_calibrate(_clock: Option<ClockType>) -> (u64, u64) {
 	let mut start: i64 = 0;
 	let mut stop: i64 = 0;
@@ -85,15 +106,15 @@ pub mod plat_windows {
 	// let freq_result = unsafe { QueryPerformanceFrequency(&mut frequency) };
 	// assert!(freq_result != 0);
 
-	let start_instant = Instant::now();
+	let start_ref = reference::now();
 	let start_result = unsafe { QueryPerformanceCounter(&mut start) };
 
-        sleep(D);
+        calibration::wait_round(false);
 
 	let stop_result = unsafe { QueryPerformanceCounter(&mut stop) };
-	let elap = start_instant.elapsed();
+	let elap = reference::now() - start_ref;
 
-	assert!(elap.as_nanos() > 0);
+	assert!(elap > 0);
 	assert!(start_result != 0);
 	assert!(stop_result != 0);
 	assert!(start > 0);
@@ -101,7 +122,7 @@ pub mod plat_windows {
         assert!(stop > start);
 
 	let ticks = stop as u64 - start as u64;
-	let nanos = elap.as_nanos() as u64;
+	let nanos = elap;
 
 	// println!("QueryPerformanceFrequency said the ticks per second is {}. Our calibration says the ticks per nanosecond is {}/{}", freq_result, ticks, nanos);
 
@@ -133,23 +154,25 @@ pub mod plat_windows {
 #[cfg(target_vendor = "apple")]
 pub mod plat_apple {
     use std::hint::black_box;
-    use crate::{ClockType, dummy_func, D};
+    use crate::{calibration, reference, ClockType, Recorder, Workload};
     extern crate libc;
     use libc::clockid_t;
     unsafe extern "C" {
         fn clock_gettime_nsec_np(clk_id: clockid_t) -> u64;
     }
 
-    /// Returns the number of this clock's nanoseconds per Instant::now() nanoseconds, in (numer,
-    /// denomer) format. Sleeps for about a millisecond in order to calibrate.
+    /// Returns the number of this clock's nanoseconds per the reference clock's nanoseconds, in
+    /// (numer, denomer) format. Waits for one calibration round (see `calibration::wait_round()`).
     pub fn gettime_nsec_np_clock_calibrate(clock: Option<ClockType>) -> (u64, u64) {
         let ct = clock.unwrap();
 
-        let start_instant = Instant::now();
+        let cputime = ct == libc::CLOCK_THREAD_CPUTIME_ID || ct == libc::CLOCK_PROCESS_CPUTIME_ID;
+
+        let start_ref = reference::now();
         let prev = unsafe { clock_gettime_nsec_np(ct) };
-        sleep(D);
+        calibration::wait_round(cputime);
         let now = unsafe { clock_gettime_nsec_np(ct) };
-        let elap = start_instant.elapsed().as_nanos() as u64;
+        let elap = reference::now() - start_ref;
 
         assert!(elap > 0);
         assert!(now > prev);
@@ -159,47 +182,49 @@ pub mod plat_apple {
         (dur, elap)
     }
 
-    pub fn gettime_nsec_np_clock(clock: Option<ClockType>, iters: u64) -> Vec<u64> {
-        let mut durations = Vec::with_capacity(iters as usize);
+    /// Reads the clock, in nanoseconds.
+    pub fn gettime_nsec_np_clock_now(clock: Option<ClockType>) -> u64 {
+        unsafe { clock_gettime_nsec_np(clock.unwrap()) }
+    }
+
+    pub fn gettime_nsec_np_clock(clock: Option<ClockType>, iters: u64, workload: &Workload, rec: &mut Recorder) {
         let mut i = 0;
         let ct = clock.unwrap();
     
         while i < iters {
             let prev = unsafe { clock_gettime_nsec_np(ct) };
 
-            black_box(dummy_func());
+            black_box(workload.run());
 
             let now = unsafe { clock_gettime_nsec_np(ct) };
 
             if now > prev {
                 let dur: u64 = now - prev;
 
-                durations.push(dur);
+                rec.record(dur);
+            } else {
+                rec.skip();
             }
 
             i += 1;
         }
-
-        durations
     }
 
     use mach_sys::mach_time::{mach_absolute_time};
-    use std::time::Instant;
-    use std::thread::sleep;
 
-    /// Returns the number of this clock's ticks per Instant::now()'s nanoseconds, in (numer,
-    /// denomer) format. Sleeps for about a millisecond in order to calibrate.
+    /// Returns the number of this clock's ticks per the reference clock's nanoseconds, in (numer,
+    /// denomer) format. Waits for one calibration round (see `calibration::wait_round()`).
     pub fn mach_absolute_time_ticks_calibrate(_clock: Option<ClockType>) -> (u64, u64) {
         //let mut mtt1: MaybeUninit<mach_timebase_info> = MaybeUninit::uninit();
         //let retval = unsafe { mach_timebase_info(mtt1.as_mut_ptr()) };
         //assert_eq!(retval, KERN_SUCCESS);
         //let mtt2 = unsafe { mtt1.assume_init() };
 
-        let start_instant = Instant::now();
+        let start_ref = reference::now();
         let t1 = unsafe { mach_absolute_time() };
-        sleep(D);
+        calibration::wait_round(false);
         let t2 = unsafe { mach_absolute_time() };
-        let elap = start_instant.elapsed().as_nanos() as u64;
+        let elap = reference::now() - start_ref;
 
         assert!(elap > 0);
         assert!(t2 > t1);
@@ -209,7 +234,12 @@ pub mod plat_apple {
         (ticks, elap)
     }
 
-    pub fn mach_absolute_time_ticks(_clock: Option<ClockType>, iters: u64) -> Vec<u64> {
+    /// Reads mach_absolute_time(), in ticks.
+    pub fn mach_absolute_time_ticks_now(_clock: Option<ClockType>) -> u64 {
+        unsafe { mach_absolute_time() }
+    }
+
+    pub fn mach_absolute_time_ticks(_clock: Option<ClockType>, iters: u64, workload: &Workload, rec: &mut Recorder) {
         //let mut mtt1: MaybeUninit<mach_timebase_info> = MaybeUninit::uninit();
         //let retval = unsafe { mach_timebase_info(mtt1.as_mut_ptr()) };
         //assert_eq!(retval, KERN_SUCCESS);
@@ -217,25 +247,24 @@ pub mod plat_apple {
 
         //eprintln!("mach_timebase_info: {mtt2:?}");
 
-        let mut durations = Vec::with_capacity(iters as usize);
         let mut i = 0;
     
         while i < iters {
             let t1 = unsafe { mach_absolute_time() };
 
-            black_box(dummy_func());
+            black_box(workload.run());
 
             let t2 = unsafe { mach_absolute_time() };
-            assert!(t2 > t1);
 
-            let ticks = t2 - t1;
-            assert!(ticks > 0);
-            durations.push(ticks);
+            if t2 > t1 {
+                let ticks = t2 - t1;
+                rec.record(ticks);
+            } else {
+                rec.skip();
+            }
 
             i += 1;
         }
-
-        durations
     }
 }
     
@@ -248,22 +277,22 @@ type ClockType = i32;
 use std::time::Duration;
 use std::thread::sleep;
 
-/// Returns the number of this clock's nanoseconds per Instant::now() nanoseconds, in (numer,
-/// denomer) format. Sleeps for about a millisecond in order to calibrate. Note that it is using the
-/// same clock for both of the measurements, so this is actually measuring nothing but the error in
-/// our calibration tecnnique. :-} Most of the reason we are doing this at all is the insert a 1sec
-/// delay before beginning the measurements of the clock, so that all of the measurements of clocks,
-/// which are done on separate threads, will be running simultaneously.
+/// Returns the number of Instant's nanoseconds per the reference clock's nanoseconds, in (numer,
+/// denomer) format. Waits for one calibration round (see `calibration::wait_round()`). Note that
+/// with the default reference, which is `Instant` itself, it is using the same clock for both of
+/// the measurements, so this is actually measuring nothing but the error in our calibration
+/// tecnnique. :-} (This used to double as a way to get all of the measurement threads started at
+/// about the same time; these days they wait at a barrier for that.)
 fn instant_calibrate(_clock: Option<ClockType>) -> (u64, u64) {
-    let start_instant1 = Instant::now();
-    let start_instant2 = Instant::now();
-    sleep(D);
-    let elap2 = start_instant2.elapsed().as_nanos() as u64;
-    let elap1 = start_instant1.elapsed().as_nanos() as u64;
-    assert!(elap1 > 0);
-    assert!(elap2 > 0);
-
-    (elap2, elap1)
+    let start_ref = reference::now();
+    let start_instant = Instant::now();
+    calibration::wait_round(false);
+    let elap = start_instant.elapsed().as_nanos() as u64;
+    let refelap = reference::now() - start_ref;
+    assert!(elap > 0);
+    assert!(refelap > 0);
+
+    (elap, refelap)
 }
 
 #[cfg(unix)]
@@ -271,22 +300,24 @@ pub mod plat_unixes {
     pub extern crate libc;
     use std::io::Error;
     use std::mem::MaybeUninit;
-    use crate::{ClockType, D, Instant, sleep, black_box, dummy_func};
+    use crate::{calibration, reference, ClockType, black_box, Recorder, Workload};
 
-    /// Returns the number of this clock's nanoseconds per Instant::now() nanoseconds, in (numer,
-    /// denomer) format. Sleeps for about a millisecond in order to calibrate.
+    /// Returns the number of this clock's nanoseconds per the reference clock's nanoseconds, in
+    /// (numer, denomer) format. Waits for one calibration round (see `calibration::wait_round()`).
     pub fn libc_gettime_clock_calibrate(clock: Option<ClockType>) -> (u64, u64) {
 	let ct = clock.unwrap();
 
 	let mut tp1: MaybeUninit<libc::timespec> = MaybeUninit::uninit();
 	let mut tp2: MaybeUninit<libc::timespec> = MaybeUninit::uninit();
 
-	let start_instant = Instant::now();
+	let cputime = ct == libc::CLOCK_THREAD_CPUTIME_ID || ct == libc::CLOCK_PROCESS_CPUTIME_ID;
+
+	let start_ref = reference::now();
 	let retval1 = unsafe { libc::clock_gettime(ct, tp1.as_mut_ptr()) };
-	sleep(D);
+	calibration::wait_round(cputime);
 	let retval2 = unsafe { libc::clock_gettime(ct, tp2.as_mut_ptr()) };
-	let elap = start_instant.elapsed();
-	assert!(elap.as_nanos() > 0);
+	let elap = reference::now() - start_ref;
+	assert!(elap > 0);
 
 	assert_eq!(retval1, 0);
 	let instsec = unsafe { (*tp1.as_ptr()).tv_sec };
@@ -299,11 +330,19 @@ pub mod plat_unixes {
 	let durnanosi64 = (newinstsec - instsec) * 1_000_000_000 + newinstnsec - instnsec;
 	assert!(durnanosi64 > 0);
 	let durnanos: u64 = durnanosi64.try_into().unwrap();
-	(durnanos, elap.as_nanos() as u64)
+	(durnanos, elap)
+    }
+
+    /// Reads the clock, in nanoseconds.
+    pub fn libc_gettime_clock_now(clock: Option<ClockType>) -> u64 {
+        let mut tp: MaybeUninit<libc::timespec> = MaybeUninit::uninit();
+        let retval = unsafe { libc::clock_gettime(clock.unwrap(), tp.as_mut_ptr()) };
+        assert_eq!(retval, 0);
+        let tp = unsafe { tp.assume_init() };
+        tp.tv_sec as u64 * 1_000_000_000 + tp.tv_nsec as u64
     }
 
-    pub fn libc_gettime_clock(clock: Option<ClockType>, iters: u64) -> Vec<u64> {
-	let mut durations = Vec::with_capacity(iters as usize);
+    pub fn libc_gettime_clock(clock: Option<ClockType>, iters: u64, workload: &Workload, rec: &mut Recorder) {
 	let mut i = 0;
 	let ct = clock.unwrap();
 	
@@ -313,7 +352,7 @@ pub mod plat_unixes {
 
             let retval1 = unsafe { libc::clock_gettime(ct, tp1.as_mut_ptr()) };
 
-            black_box(dummy_func());
+            black_box(workload.run());
 
             let retval2 = unsafe { libc::clock_gettime(ct, tp2.as_mut_ptr()) };
 
@@ -330,13 +369,13 @@ pub mod plat_unixes {
                 let durnanos: u64 = durnanosi64.try_into().unwrap();
                 assert!(durnanos > 0);
 
-                durations.push(durnanos);
+                rec.record(durnanos);
+            } else {
+                rec.skip();
             }
 
             i += 1;
 	}
-
-	durations
     }
 
     pub fn increment_system_time() {
@@ -366,49 +405,49 @@ pub mod plat_unixes {
 
 #[cfg(target_arch = "x86_64")]
 pub mod plat_x86_64 {
-    use crate::{ClockType, dummy_func, D};
+    use crate::{calibration, reference, ClockType, Recorder, Workload};
     use core::arch::x86_64;
     use std::hint::black_box;
-    use std::thread::sleep;
-    use std::time::Instant;
 
-    pub fn rdtscp(_clock: Option<ClockType>, iters: u64) -> Vec<u64> {
+    pub fn rdtscp(_clock: Option<ClockType>, iters: u64, workload: &Workload, rec: &mut Recorder) {
         let mut aux = 0;
-
-        let mut res = Vec::with_capacity(iters as usize);
         let mut i = 0;
         
         while i < iters {
             let now1 = unsafe { x86_64::__rdtscp(&mut aux) };
 
-            black_box(dummy_func());
+            black_box(workload.run());
 
             let now2 = unsafe { x86_64::__rdtscp(&mut aux) };
 
             debug_assert!(now2 > now1);
             let ticks = now2 as u64 - now1 as u64;
 
-            res.push(ticks);
+            rec.record(ticks);
 
             i += 1;
         }
+    }
 
-        res
+    /// Reads the tsc, in ticks.
+    pub fn rdtscp_now(_clock: Option<ClockType>) -> u64 {
+        let mut aux = 0;
+        unsafe { x86_64::__rdtscp(&mut aux) }
     }
 
-    /// Returns the number of tsc ticks per nanosecond, in (numer, denomer) format.
-    /// Sleeps for about a millisecond in order to calibrate.
+    /// Returns the number of tsc ticks per reference nanosecond, in (numer, denomer) format.
+    /// Waits for one calibration round (see `calibration::wait_round()`).
     pub fn rdtscp_calibrate(_clock: Option<ClockType>) -> (u64, u64) {
         let mut aux = 0;
-        let start_instant = Instant::now();
+        let start_ref = reference::now();
         let start_tsc = unsafe { x86_64::__rdtscp(&mut aux) };
-        sleep(D);
+        calibration::wait_round(false);
         let end_tsc = unsafe { x86_64::__rdtscp(&mut aux) };
-        let elap = start_instant.elapsed();
+        let elap = reference::now() - start_ref;
         assert!(end_tsc > start_tsc);
-        assert!(elap.as_nanos() > 0);
+        assert!(elap > 0);
 
-        ((end_tsc - start_tsc).into(), elap.as_nanos() as u64)
+        (end_tsc - start_tsc, elap)
     }
 }
 
@@ -421,108 +460,434 @@ fn jump_clock_forward_1_sec() {
     plat_windows::increment_system_time()
 }
 
-fn stats<F, G>(func: F, calibrate: G, clock: Option<ClockType>, fnname: &str, clockname: &str, scale: bool)
-where
-    F: Fn(Option<ClockType>, u64) -> Vec<u64>,
-    G: Fn(Option<ClockType>) -> (u64, u64),
-{
-    let iters = get_iters();
+/// How much is cut off each end for the trimmed and winsorized means: 10%.
+const TRIM_FRACTION: f64 = 0.10;
+
+/// The results of measuring one clock on one thread, i.e. one row of the table.
+pub struct Summary {
+    pub fnname: &'static str,
+    pub clockname: &'static str,
+    pub run: RunInfo,
+    pub numsamples: u64,
+    /// How many samples read as zero or negative and were left out of `numsamples`.
+    pub skipped: u64,
+    pub min: u64,
+    pub perc50: u64,
+    pub mean: i64,
+    pub perc95: u64,
+    pub max: u64,
+    /// The `--percentiles=` quantiles, in the same order as `get_percentiles()`.
+    pub percentiles: Vec<u64>,
+    /// The most common value.
+    pub mode: u64,
+    /// The interquartile range, perc75 - perc25.
+    pub iqr: u64,
+    /// The median absolute deviation from the median.
+    pub mad: u64,
+    /// The mean with the lowest and highest `TRIM_FRACTION` of the samples left out.
+    pub trimmed: i64,
+    /// The mean with the lowest and highest `TRIM_FRACTION` of the samples clamped to the
+    /// remaining ones.
+    pub winsorized: i64,
+    /// Bootstrapped confidence intervals, if `--bootstrap` is on.
+    pub intervals: Vec<bootstrap::Interval>,
+    /// The samples, kept for `--compare`.
+    pub hist: Option<histogram::Histogram>,
+    pub stddev: f64,
+    /// This clock's rate relative to the reference clock's, or None for clocks whose calibration
+    /// was used to scale their ticks into nanoseconds.
+    pub drift: Option<f64>,
+    pub calibration: Calibration,
+    /// For scaled clocks under `--ticks`: the statistics in raw ticks.
+    pub ticks: Option<TickStats>,
+    /// The clock's empty read-to-read time, under `--subtract-overhead`.
+    pub overhead: Option<u64>,
+    /// The samples split into uninterrupted and preempted, under `--preemption`.
+    pub classes: Option<preemption::Classified>,
+    /// The samples above the `--outliers=` threshold.
+    pub outliers: Option<outliers::Outliers>,
+    /// The clusters the samples fall into, lowest first. More than one means the row is
+    /// multimodal, and its mean isn't close to any of them.
+    pub modes: Vec<modes::Mode>,
+    /// The periods the samples' durations repeat with, under `--periodicity`.
+    pub periods: Option<periodicity::Periodicity>,
+    /// For a clock measured by several threads at once, each thread's own summary, in thread
+    /// order; this one is their aggregate.
+    pub threads: Vec<Summary>,
+}
 
-    let (numer, denomer) = calibrate(clock);
-    let durations = func(clock, iters);
+/// A scaled clock's samples in raw ticks, under `--ticks`.
+#[derive(Clone, Copy)]
+pub struct TickStats {
+    pub min: u64,
+    pub perc50: u64,
+    pub mean: f64,
+    pub perc95: u64,
+    pub max: u64,
+    pub stddev: f64,
+}
+
+/// Where and when a measurement ran.
+#[derive(Clone)]
+pub struct RunInfo {
+    /// The CPU(s) it ran on, see `affinity::format_cpus()`.
+    pub cpus: String,
+    /// When the measurement loop started and finished, relative to `run_epoch()`.
+    pub started: Duration,
+    pub finished: Duration,
+}
+
+/// The moment main() started, which the start and finish times of every measurement are
+/// reported relative to.
+pub fn run_epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
 
-    let mut map: FxHashMap<u64, u64> = FxHashMap::default();
+/// Holds a measurement thread's place at the start barrier, and waits there when dropped if
+/// `start()` hasn't been called, so that a thread that panics while getting ready still lets the
+/// others go, and its panic surfaces when it's joined rather than every other thread hanging.
+pub struct StartGuard<'a> {
+    barrier: Option<&'a Barrier>,
+}
+
+impl<'a> StartGuard<'a> {
+    pub fn new(barrier: Option<&'a Barrier>) -> StartGuard<'a> {
+        StartGuard { barrier }
+    }
 
-    if scale {
-        for dur in durations {
-            let nanos = dur * denomer / numer;
-            *map.entry(nanos).or_insert(0) += 1;
+    /// Waits until every other thread at the barrier is ready too.
+    pub fn start(&mut self) {
+        if let Some(barrier) = self.barrier.take() {
+            barrier.wait();
         }
-    } else {
-        for dur in durations {
-            *map.entry(dur).or_insert(0) += 1;
+    }
+}
+
+impl Drop for StartGuard<'_> {
+    fn drop(&mut self) {
+        self.start();
+    }
+}
+
+/// What a measurement thread hands back: its samples and their summary, or with `--sweep`, its
+/// samples at each size.
+enum Measured {
+    Stats(Box<(Summary, Recorder)>),
+    Sweep(sweep::Swept),
+}
+
+/// Waits for every thread measuring each clock, clock by clock in the order they were started
+/// (not the order they finish in), and prints a row for each clock, merging its threads' results
+/// into one if there are several. With `--sweep`, that's a row of the sweep table for the sizes.
+fn report_clocks(clocks: impl Iterator<Item = (ClockFn, Vec<JoinHandle<Measured>>)>, sweepsizes: Option<&[u64]>) -> Vec<Summary> {
+    let mut summaries = Vec::new();
+    for (cf, handles) in clocks {
+        let mut results: Vec<(Summary, Recorder)> = Vec::new();
+        let mut swept = Vec::new();
+        for handle in handles {
+            match handle.join().unwrap() {
+                Measured::Stats(result) => results.push(*result),
+                Measured::Sweep(s) => swept.push(s),
+            }
+        }
+        if let Some(sizes) = sweepsizes
+            && !swept.is_empty()
+        {
+            println!("{}", sweep::row(&cf, sizes, swept));
         }
+        if results.is_empty() {
+            continue;
+        }
+        let summary = aggregate::aggregate(&cf, results);
+        print_row(&summary);
+        summaries.push(summary);
     }
-    
-    let mut pairs: Vec<(&u64, &u64)> = map.iter().collect();
+    summaries
+}
 
-    pairs.sort_by(|a, b| a.0.cmp(b.0));
+/// Calibrates the clock, runs any `--warmup` iterations, waits at `start` (if given) until every
+/// other measurement thread is ready too, and then takes as many samples as the budget says.
+fn measure(cf: &ClockFn, start: Option<&Barrier>) -> Summary {
+    measure_recorded(cf, start).0
+}
 
-    let mut perc50: u64 = 0;
-    let mut perc95: u64 = 0;
-    let min: u64 = *pairs[0].0;
-    let max: u64 = *pairs[(*pairs).len()-1].0;
+/// Like `measure()`, but also returns the samples, for merging with other threads'.
+fn measure_recorded(cf: &ClockFn, start: Option<&Barrier>) -> (Summary, Recorder) {
+    let mut guard = StartGuard::new(start);
+    let workload = get_workload();
+
+    let cpustart = affinity::current_cpu();
+    let calibration = calibration::calibrate(cf);
+    warm_up(cf, workload);
+    let overhead = overhead::get_subtract_overhead().then(|| overhead::measure_overhead(cf, &calibration));
+    let mut outliers = outliers::get_outliers().map(|settings| outliers::Outliers::new(cf, workload, &calibration, settings));
+    // Made (and under --lownoise, prefaulted) before the start, so that it doesn't hold this
+    // thread up while the others are already measuring.
+    let mut rec = new_recorder(cf, &calibration);
+    guard.start();
+
+    let mut classes = preemption::get_preemption().map(|slack| (slack, preemption::Classified::new()));
+    let started = run_epoch().elapsed();
+    {
+        let mut sample = |n, rec: &mut Recorder| match &mut classes {
+            Some((slack, c)) => preemption::take_classified(cf, workload, n, *slack, rec, c),
+            None => (cf.func)(cf.clock, n, workload, rec),
+        };
+        match &mut outliers {
+            Some(o) => budget::take_samples_with(cf, &budget::get_budget(), &mut rec, |n, rec| o.take_logged(n, rec, &mut sample)),
+            None => budget::take_samples_with(cf, &budget::get_budget(), &mut rec, sample),
+        }
+    }
+    let finished = run_epoch().elapsed();
+    let cpus = affinity::format_cpus(cpustart, affinity::current_cpu());
 
-    let mut numsamples: u64 = 0;
-    for (_nanos, num) in &pairs {
-        numsamples += *num;
+    let mut summary = summarize(cf, calibration, overhead, &rec, RunInfo { cpus, started, finished });
+    summary.classes = classes.map(|(_, c)| c);
+    summary.outliers = outliers;
+    (summary, rec)
+}
+
+/// Takes `--warmup=N` samples and throws them away.
+pub fn warm_up(cf: &ClockFn, workload: &Workload) {
+    let warmup = get_warmup();
+    if warmup > 0 {
+        let mut rec = Recorder::new(None);
+        (cf.func)(cf.clock, warmup, workload, &mut rec);
+        black_box(rec);
     }
-    
-    //println!("{:>10} {:>10} {:>10} {:>10}", "nanos", "#", "# < nanos", "# > nanos");
-    //println!("{:>10} {:>10} {:>10} {:>10}", "-----", "-", "---------", "---------");
-    let mut sumnanos = 0;
-    let mut sumnums = 0;
-    for (nanos, num) in &pairs {
-        if (sumnums + *num >= numsamples * 95 / 100) && (sumnums < numsamples * 95 / 100) {
-            //println!("95 percentile");
-            perc95 = **nanos;
+}
+
+/// Computes the summary statistics of one clock's samples, given its calibration and, with
+/// `--subtract-overhead`, its read-to-read overhead.
+fn summarize(cf: &ClockFn, calibration: Calibration, overhead: Option<u64>, rec: &Recorder, run: RunInfo) -> Summary {
+    let ClockFn { fnname, clockname, scale, .. } = *cf;
+
+    let numsamples = rec.count;
+    let skipped = rec.skipped;
+    let min = if numsamples > 0 { rec.min } else { 0 };
+    let max = rec.max;
+
+    let perc50 = rec.quantile(0.50);
+    let perc95 = rec.quantile(0.95);
+    let percentiles = get_percentiles().iter().map(|&(_, q)| rec.quantile(q)).collect();
+
+    let mean = rec.mean.round() as i64;
+    let stddev = rec.stddev();
+
+    let mode = rec.mode();
+    let modes = modes::find_modes(rec);
+    let iqr = rec.quantile(0.75) - rec.quantile(0.25);
+    let mad = rec.mad();
+    let trimmed = rec.trimmed_mean(TRIM_FRACTION).round() as i64;
+    let winsorized = rec.winsorized_mean(TRIM_FRACTION).round() as i64;
+
+    let drift = if scale { None } else { Some(calibration.ratio()) };
+
+    let intervals = match bootstrap::get_bootstrap() {
+        Some(bs) => bootstrap::intervals(rec, get_percentiles(), &bs),
+        None => Vec::new(),
+    };
+    let hist = significance::get_compare().map(|_| rec.hist.clone());
+    let ticks = rec.ticks.as_ref().map(|t| TickStats { min: t.min, perc50: t.quantile(0.5), mean: t.mean, perc95: t.quantile(0.95), max: t.max, stddev: t.stddev() });
+    let spacing = (run.finished - run.started).as_nanos() as f64 / numsamples.max(1) as f64;
+    let periods = rec.series.as_ref().map(|series| periodicity::find_periods(series, spacing));
+
+    Summary { fnname, clockname, run, numsamples, skipped, min, perc50, mean, perc95, max, percentiles, mode, iqr, mad, trimmed, winsorized, stddev, drift, calibration, ticks, overhead, classes: None, outliers: None, modes, periods, threads: Vec::new(), intervals, hist }
+}
+
+/// The `--percentiles=` columns of the main table go in place of the default perc50 and perc95,
+/// each as wide as the max column, as no percentile can be wider than the max, or as its header if
+/// that's wider still.
+fn percentile_width(label: &str) -> usize {
+    (label.len() + 4).max(14)
+}
+
+/// The index of the percentile the mean column goes after: the median if it's among the
+/// `--percentiles=`, as it is by default, otherwise the last of them.
+fn mean_after(percs: &[(String, f64)]) -> usize {
+    percs.iter().position(|&(_, q)| q == 0.5).unwrap_or(percs.len() - 1)
+}
+
+fn print_header() {
+    let percs = get_percentiles();
+    let mut names = Vec::new();
+    let mut dashes = Vec::new();
+    for (i, (label, _)) in percs.iter().enumerate() {
+        let name = format!("perc{label}");
+        let w = percentile_width(label);
+        names.push(format!("{name:>w$}"));
+        dashes.push(format!("{:>w$}", "-".repeat(name.len())));
+        if i == mean_after(percs) {
+            names.push(format!("{:>11}", "mean"));
+            dashes.push(format!("{:>11}", "----"));
+        }
+    }
+    println!("{:>38} {:>14} {:>5} {:>10} {:>10} {:>12} {:>7} {} {:>14} {:>7} {:>7} {:>7} {:>11} {:>11} {:>11} {:>12}", "fnname", "clock", "cpu", "startus", "finishus", "nsamples", "min", names.join(" "), "max", "mode", "iqr", "mad", "trimmean", "winsmean", "stddev", "drift");
+    println!("{:>38} {:>14} {:>5} {:>10} {:>10} {:>12} {:>7} {} {:>14} {:>7} {:>7} {:>7} {:>11} {:>11} {:>11} {:>12}", "------", "-----", "---", "-------", "--------", "--------", "---", dashes.join(" "), "---", "----", "---", "---", "--------", "--------", "------", "-----");
+}
+
+fn print_row(s: &Summary) {
+    let Summary { fnname, clockname, run, .. } = s;
+    // A calibration that was mostly off the CPU gives a drift that means nothing.
+    let drift = match s.drift {
+        Some(drift) if !s.calibration.off_cpu() => format!("{drift:.6}"),
+        _ => "---".to_string(),
+    };
+    let percs = get_percentiles();
+    let mut cols = Vec::new();
+    for (i, ((label, _), p)) in percs.iter().zip(&s.percentiles).enumerate() {
+        let w = percentile_width(label);
+        cols.push(format!("{:>w$}", p.separate_with_commas()));
+        if i == mean_after(percs) {
+            cols.push(format!("{:>11}", s.mean.separate_with_commas()));
+        }
+    }
+    let mut row = format!("{fnname:>38} {clockname:>14} {:>5} {:>10} {:>10} {:>12} {:>7} {} {:>14} {:>7} {:>7} {:>7} {:>11} {:>11} {:>11} {drift:>12}", run.cpus, (run.started.as_micros() as u64).separate_with_commas(), (run.finished.as_micros() as u64).separate_with_commas(), s.numsamples.separate_with_commas(), s.min.separate_with_commas(), cols.join(" "), s.max.separate_with_commas(), s.mode.separate_with_commas(), s.iqr.separate_with_commas(), s.mad.separate_with_commas(), s.trimmed.separate_with_commas(), s.winsorized.separate_with_commas(), (s.stddev as u128).separate_with_commas());
+
+    // Everything else goes on lines of its own under the row, each ending in something other than
+    // a number so that parse-results.py doesn't take it for a row. They're printed in one go with
+    // the row so that other threads' rows can't get in between.
+    //
+    // Left-out samples first, as a clock that often reads zero makes the rest of the row look
+    // slower than the clock is.
+    if s.skipped > 0 {
+        row.push_str(&format!("\n{:>38} skipped: {} samples that read zero or less", "", s.skipped.separate_with_commas()));
+    }
+    // A multimodal row's mean falls between its modes, so they get a line of their own.
+    if s.modes.len() > 1 {
+        row.push_str(&format!("\n{:>38} {}", "", modes::describe(&s.modes)));
+    }
+
+    if !s.threads.is_empty() {
+        row.push_str(&format!("\n{:>38} {}", "", aggregate::describe_spread(&s.threads)));
+        if aggregate::get_per_thread() {
+            for (i, t) in s.threads.iter().enumerate() {
+                row.push_str(&format!("\n{:>38} {}", "", aggregate::describe_thread(i, t)));
+            }
         }
-        if (sumnums + *num >= numsamples * 50 / 100) && (sumnums < numsamples * 50 / 100) {
-            //println!("50 percentile");
-            perc50 = **nanos;
+    }
+
+    // The durations net of the clock's own overhead, and/or in TSC cycles.
+    if let Some(overhead) = s.overhead {
+        let net = |v: u64| v.saturating_sub(overhead);
+        row.push_str(&format!("\n{:>38} overhead: {}ns; net of it: perc50 {}ns mean {}ns perc95 {}ns", "", overhead.separate_with_commas(), net(s.perc50).separate_with_commas(), (s.mean - overhead as i64).separate_with_commas(), net(s.perc95).separate_with_commas()));
+    }
+    if overhead::get_cycles() {
+        let rate = overhead::tsc_ticks_per_ns();
+        let cycles = |ns: f64| ((ns * rate).round() as i64).separate_with_commas();
+        row.push_str(&format!("\n{:>38} cycles: perc50 {} mean {} perc95 {}", "", cycles(s.perc50 as f64), cycles(s.mean as f64), cycles(s.perc95 as f64)));
+        if let Some(overhead) = s.overhead {
+            let o = overhead as f64;
+            row.push_str(&format!("; net: perc50 {} mean {} perc95 {}", cycles((s.perc50 as f64 - o).max(0.0)), cycles(s.mean as f64 - o), cycles((s.perc95 as f64 - o).max(0.0))));
         }
-        sumnanos += *nanos * *num;
-        sumnums += *num;
+        row.push_str(&format!(" (at {rate:.4} TSC ticks/ns)"));
     }
-    assert!(sumnanos < i64::MAX as u64);
-    let mean: i64 = (sumnanos / numsamples).try_into().unwrap();
 
-    if perc50 == 0 {
-        perc50 = *pairs[pairs.len()-1].0;
+    if let Some(classes) = &s.classes {
+        row.push_str(&format!("\n{:>38} {}", "", preemption::format_class("uninterrupted", &classes.uninterrupted)));
+        row.push_str(&format!("\n{:>38} {} ({} with context switches)", "", preemption::format_class("preempted", &classes.preempted), classes.switched.separate_with_commas()));
     }
-    if perc95 == 0 {
-        perc95 = *pairs[pairs.len()-1].0;
+
+    if let Some((outliers, settings)) = s.outliers.as_ref().zip(outliers::get_outliers()) {
+        row.push_str(&format!("\n{:>38} {}", "", outliers.describe(&settings.path)));
     }
 
-    let mut sumsquares: f64 = 0f64;
-    for (nanos, num) in pairs {
-        let f64ns: f64 = *nanos as f64;
-        let diff: f64 = f64ns - mean as f64;
-        let sqdiff: f64 = diff.powf(2f64);
-        let n: f64 = (*num) as f64;
-        sumsquares += sqdiff * n;
-//        eprintln!("xyz, sumsquares: {}, sqdiff: {}, n: {}", sumsquares as u128, sqdiff as u128, n as u128);
+    if let Some(periods) = &s.periods {
+        row.push_str(&format!("\n{:>38} {}", "", periods.describe()));
+    }
+    for (i, t) in s.threads.iter().enumerate() {
+        if let Some(periods) = &t.periods {
+            row.push_str(&format!("\n{:>38} thread {i} {}", "", periods.describe()));
+        }
     }
-    let stddev = (sumsquares / (numsamples - 1) as f64).sqrt();
 
-    if scale {
-        println!("{fnname:>38} {clockname:>14} {:>12} {:>7} {:>7} {:>11} {:>7} {:>14} {:>11} {:>12}", numsamples.separate_with_commas(), min.separate_with_commas(), perc50.separate_with_commas(), mean.separate_with_commas(), perc95.separate_with_commas(), max.separate_with_commas(), (stddev as u128).separate_with_commas(), "---");
-    } else {
-        let drift = numer as f64 / denomer as f64;
-        println!("{fnname:>38} {clockname:>14} {:>12} {:>7} {:>7} {:>11} {:>7} {:>14} {:>11} {:>12.6}", numsamples.separate_with_commas(), min.separate_with_commas(), perc50.separate_with_commas(), mean.separate_with_commas(), perc95.separate_with_commas(), max.separate_with_commas(), (stddev as u128).separate_with_commas(), drift);
+    // Raw ticks.
+    if let Some(TickStats { min, perc50, mean, perc95, max, stddev }) = s.ticks {
+        row.push_str(&format!("\n{:>38} ticks: min {} perc50 {} mean {} perc95 {} max {} stddev {} (at {:.6} ticks/ns)", "", min.separate_with_commas(), perc50.separate_with_commas(), (mean.round() as u64).separate_with_commas(), perc95.separate_with_commas(), max.separate_with_commas(), (stddev as u128).separate_with_commas(), s.calibration.ratio()));
+    }
+
+    // Calibration that varied, that was mostly off the CPU, or that was asked about with
+    // --calibrate=.
+    let cal = &s.calibration;
+    if cal.varies() || cal.skewed() || cal.off_cpu() || calibration::get_calibration_settings().explicit {
+        let unit = if s.drift.is_none() { " ticks/ns" } else { "" };
+        let err = match cal.relerr {
+            Some(e) => format!("{:.6}", e * cal.ratio()),
+            None => "?".to_string(),
+        };
+        let how = if calibration::spins(cal.cputime) { "spinning" } else { "sleeping" };
+        row.push_str(&format!("\n{:>38} calibration: {:.6}{unit} +/- {err} (std err) from {} rounds of {:?} {how}, {} dropped as outliers", "", cal.ratio(), cal.rounds, calibration::caltime(), cal.dropped));
+        if cal.preempted > 0 {
+            row.push_str(&format!("; {} retaken as the thread got switched out", cal.preempted));
+            if cal.off_cpu() {
+                row.push_str(" too often to tell whether it varies or is skewed");
+            }
+        }
+        if cal.varies() {
+            row.push_str(&format!("; VARIES by {:.3}% between rounds", cal.spread * 100.0));
+        }
+        if cal.skewed() {
+            row.push_str(&format!("; SKEWED by {:.3}% from wall time while on the CPU", (cal.ratio() - 1.0) * 100.0));
+        }
     }
 
+    if let Some(bs) = bootstrap::get_bootstrap()
+        && !s.intervals.is_empty()
+    {
+        let cis: Vec<String> = s.intervals.iter().map(|ci| format!("{} [{}, {}]", ci.name, (ci.lo.round() as u64).separate_with_commas(), (ci.hi.round() as u64).separate_with_commas())).collect();
+        row.push_str(&format!("\n{:>38} ci{}: {}", "", bs.level * 100.0, cis.join("  ")));
+    }
+    println!("{row}");
 }
 
 use thousands::Separable;
 
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::sync::{Arc, Barrier, OnceLock};
+
+/// A clock to be measured: the function that takes its samples, the function that calibrates it
+/// against the reference clock, the function that reads it, and how to label and scale its
+/// results.
+#[derive(Clone, Copy)]
+pub struct ClockFn {
+    pub func: fn(Option<ClockType>, u64, &Workload, &mut Recorder),
+    pub calibrate: fn(Option<ClockType>) -> (u64, u64),
+    /// Reads the clock, in nanoseconds, or in ticks for clocks that need scaling.
+    pub now: fn(Option<ClockType>) -> u64,
+    pub clock: Option<ClockType>,
+    pub fnname: &'static str,
+    pub clockname: &'static str,
+    pub scale: bool,
+}
+
+impl ClockFn {
+    /// e.g. "libc_gettime_clock MONOTONIC", for messages.
+    pub fn label(&self) -> String {
+        format!("{} {}", self.fnname, self.clockname)
+    }
+}
 
 macro_rules! add_wrapped_fn {
-    ($vec:expr, $func:path, $calibrate:path, $clock:expr, $scale:expr) => {
-        $vec.push(move || {
-            // Full stringified clock (e.g., "Some(libc::CLOCK_THREAD_CPUTIME_ID)")
-            let clock_str = stringify!($clock);
-
-            let mut pruned_clockname = clock_str;
-            if let Some(s) = pruned_clockname.strip_prefix("Some(") { pruned_clockname = s; }
-            if let Some(s) = pruned_clockname.strip_prefix("libc::") { pruned_clockname = s; }
-            if let Some(s) = pruned_clockname.strip_prefix("CLOCK_") { pruned_clockname = s; }
-            if let Some(s) = pruned_clockname.strip_suffix(")") { pruned_clockname = s; }
-            if let Some(s) = pruned_clockname.strip_suffix("_ID") { pruned_clockname = s; }
-            
-            // Call stats with the pruned clock name
-            stats($func, $calibrate, $clock, stringify!($func), pruned_clockname, $scale);
+    ($vec:expr, $func:path, $calibrate:path, $now:path, $clock:expr, $scale:expr) => {
+        // Full stringified clock (e.g., "Some(libc::CLOCK_THREAD_CPUTIME_ID)")
+        let clock_str = stringify!($clock);
+
+        let mut pruned_clockname = clock_str;
+        if let Some(s) = pruned_clockname.strip_prefix("Some(") { pruned_clockname = s; }
+        if let Some(s) = pruned_clockname.strip_prefix("libc::") { pruned_clockname = s; }
+        if let Some(s) = pruned_clockname.strip_prefix("CLOCK_") { pruned_clockname = s; }
+        if let Some(s) = pruned_clockname.strip_suffix(")") { pruned_clockname = s; }
+        if let Some(s) = pruned_clockname.strip_suffix("_ID") { pruned_clockname = s; }
+
+        $vec.push(ClockFn {
+            func: $func,
+            calibrate: $calibrate,
+            now: $now,
+            clock: $clock,
+            fnname: stringify!($func),
+            clockname: pruned_clockname,
+            scale: $scale,
         });
     };
 }
@@ -544,6 +909,93 @@ fn get_iters() -> u64 {
     DEFAULT_ITERS
 }
 
+/// Makes the recorder a clock's samples go into, converting ticks to nanoseconds for clocks that
+/// need scaling. With `--lownoise` all of its memory is touched up front, so that the measurement
+/// loop doesn't take page faults as the histogram grows.
+pub fn new_recorder(cf: &ClockFn, calibration: &Calibration) -> Recorder {
+    let mut rec = Recorder::new(cf.scale.then(|| calibration.tick_scale()));
+    if get_show_ticks() {
+        rec.keep_ticks();
+    }
+    if let Some(limit) = periodicity::get_periodicity() {
+        rec.keep_series(limit);
+    }
+    if lownoise::get_lownoise().is_some() {
+        rec.hist.prefault();
+        if let Some(series) = &mut rec.series {
+            series.resize(series.capacity(), 0);
+            series.clear();
+        }
+        if let Some(ticks) = &mut rec.ticks {
+            ticks.hist.prefault();
+        }
+    }
+    rec
+}
+
+/// Returns whether `--ticks` was given, to report scaled clocks in raw ticks as well as in
+/// nanoseconds.
+fn get_show_ticks() -> bool {
+    static SHOW_TICKS: OnceLock<bool> = OnceLock::new();
+    *SHOW_TICKS.get_or_init(|| env::args().any(|arg| arg == "--ticks"))
+}
+
+/// Pins the calling measurement thread (if `pincpu` says to) and applies `--lownoise`, reporting
+/// anything that doesn't work.
+pub fn setup_measurement_thread(what: &str, pincpu: Option<usize>) {
+    if let Some(cpu) = pincpu
+        && let Err(e) = affinity::pin_current_thread(cpu)
+    {
+        eprintln!("Couldn't pin {what} to CPU {cpu}, leaving it unpinned: {e}");
+    }
+    if let Some(ln) = lownoise::get_lownoise()
+        && let Err(e) = lownoise::enter_realtime(&ln)
+    {
+        eprintln!("lownoise: couldn't put {what} under {ln}, leaving it as it was: {e}");
+    }
+}
+
+/// Returns the number of samples to take and throw away before measuring, from `--warmup=N`.
+fn get_warmup() -> u64 {
+    for arg in env::args() {
+        if let Some(warmup_str) = arg.strip_prefix("--warmup=") {
+            if let Ok(argwarmup) = warmup_str.parse::<u64>() {
+                return argwarmup;
+            } else {
+                panic!("--warmup=N needs a number, not {warmup_str:?}");
+            }
+        }
+    }
+
+    0
+}
+
+/// Returns the percentiles to report, from `--percentiles=LIST` (e.g. `p50,p99,p99.9`), as
+/// (label, quantile) pairs in the order given. The default is p50 and p95.
+fn get_percentiles() -> &'static [(String, f64)] {
+    static PERCENTILES: OnceLock<Vec<(String, f64)>> = OnceLock::new();
+
+    PERCENTILES.get_or_init(|| {
+        let mut percstr = "p50,p95".to_string();
+        for arg in env::args() {
+            if let Some(s) = arg.strip_prefix("--percentiles=") {
+                percstr = s.to_string();
+            }
+        }
+
+        let percs: Vec<(String, f64)> = percstr
+            .split(',')
+            .filter(|p| !p.is_empty())
+            .map(|p| {
+                let q = budget::parse_percentile(p).unwrap_or_else(|e| panic!("{e}"));
+                (p.strip_prefix('p').unwrap_or(p).to_string(), q)
+            })
+            .collect();
+        assert!(!percs.is_empty(), "--percentiles= needs at least one percentile");
+        percs
+    })
+}
+
 fn jump_clock_ahead_thread() {
     sleep(D);
 
@@ -552,65 +1004,174 @@ fn jump_clock_ahead_thread() {
     }
 }
 
+/// Says what's wrong with the command line (or with what it asks for on this machine) and exits.
+fn exit_with_error(msg: &str) -> ! {
+    eprintln!("{msg}");
+    std::process::exit(2);
+}
+
 use std::env;
 fn main() {
-    let mut fns: Vec<fn()> = Vec::new();
+    run_epoch();
+    let mut fns: Vec<ClockFn> = Vec::new();
     let mut clockmeasurementhandles = Vec::new();
 
-    add_wrapped_fn!(fns, instant, instant_calibrate, None, false);
+    add_wrapped_fn!(fns, instant, instant_calibrate, instant_now, None, false);
 
 #[cfg(unix)]
     {
-    use crate::plat_unixes::{libc, libc_gettime_clock, libc_gettime_clock_calibrate};
-    add_wrapped_fn!(fns, libc_gettime_clock, libc_gettime_clock_calibrate, Some(libc::CLOCK_THREAD_CPUTIME_ID), false);
-    add_wrapped_fn!(fns, libc_gettime_clock, libc_gettime_clock_calibrate, Some(libc::CLOCK_MONOTONIC), false);
-    add_wrapped_fn!(fns, libc_gettime_clock, libc_gettime_clock_calibrate, Some(libc::CLOCK_REALTIME), false);
-    add_wrapped_fn!(fns, libc_gettime_clock, libc_gettime_clock_calibrate, Some(libc::CLOCK_MONOTONIC_RAW), false);
+    use crate::plat_unixes::{libc, libc_gettime_clock, libc_gettime_clock_calibrate, libc_gettime_clock_now};
+    add_wrapped_fn!(fns, libc_gettime_clock, libc_gettime_clock_calibrate, libc_gettime_clock_now, Some(libc::CLOCK_THREAD_CPUTIME_ID), false);
+    add_wrapped_fn!(fns, libc_gettime_clock, libc_gettime_clock_calibrate, libc_gettime_clock_now, Some(libc::CLOCK_MONOTONIC), false);
+    add_wrapped_fn!(fns, libc_gettime_clock, libc_gettime_clock_calibrate, libc_gettime_clock_now, Some(libc::CLOCK_REALTIME), false);
+    add_wrapped_fn!(fns, libc_gettime_clock, libc_gettime_clock_calibrate, libc_gettime_clock_now, Some(libc::CLOCK_MONOTONIC_RAW), false);
     }
 #[cfg(target_vendor = "apple")]
     {
-        use crate::plat_unixes::{libc_gettime_clock, libc_gettime_clock_calibrate, libc};
-        add_wrapped_fn!(fns, plat_apple::mach_absolute_time_ticks, plat_apple::mach_absolute_time_ticks_calibrate, None, true);
-        add_wrapped_fn!(fns, libc_gettime_clock, libc_gettime_clock_calibrate, Some(libc::CLOCK_UPTIME_RAW), false);
-        add_wrapped_fn!(fns, plat_apple::gettime_nsec_np_clock, plat_apple::gettime_nsec_np_clock_calibrate, Some(libc::CLOCK_UPTIME_RAW), false);
-        add_wrapped_fn!(fns, plat_apple::gettime_nsec_np_clock, plat_apple::gettime_nsec_np_clock_calibrate, Some(libc::CLOCK_THREAD_CPUTIME_ID), false);
-        add_wrapped_fn!(fns, plat_apple::gettime_nsec_np_clock, plat_apple::gettime_nsec_np_clock_calibrate, Some(libc::CLOCK_MONOTONIC), false);
-        add_wrapped_fn!(fns, plat_apple::gettime_nsec_np_clock, plat_apple::gettime_nsec_np_clock_calibrate, Some(libc::CLOCK_MONOTONIC_RAW), false);
+        use crate::plat_unixes::{libc_gettime_clock, libc_gettime_clock_calibrate, libc_gettime_clock_now, libc};
+        add_wrapped_fn!(fns, plat_apple::mach_absolute_time_ticks, plat_apple::mach_absolute_time_ticks_calibrate, plat_apple::mach_absolute_time_ticks_now, None, true);
+        add_wrapped_fn!(fns, libc_gettime_clock, libc_gettime_clock_calibrate, libc_gettime_clock_now, Some(libc::CLOCK_UPTIME_RAW), false);
+        add_wrapped_fn!(fns, plat_apple::gettime_nsec_np_clock, plat_apple::gettime_nsec_np_clock_calibrate, plat_apple::gettime_nsec_np_clock_now, Some(libc::CLOCK_UPTIME_RAW), false);
+        add_wrapped_fn!(fns, plat_apple::gettime_nsec_np_clock, plat_apple::gettime_nsec_np_clock_calibrate, plat_apple::gettime_nsec_np_clock_now, Some(libc::CLOCK_THREAD_CPUTIME_ID), false);
+        add_wrapped_fn!(fns, plat_apple::gettime_nsec_np_clock, plat_apple::gettime_nsec_np_clock_calibrate, plat_apple::gettime_nsec_np_clock_now, Some(libc::CLOCK_MONOTONIC), false);
+        add_wrapped_fn!(fns, plat_apple::gettime_nsec_np_clock, plat_apple::gettime_nsec_np_clock_calibrate, plat_apple::gettime_nsec_np_clock_now, Some(libc::CLOCK_MONOTONIC_RAW), false);
     }
 #[cfg(target_arch = "x86_64")]
-    add_wrapped_fn!(fns, plat_x86_64::rdtscp, plat_x86_64::rdtscp_calibrate, None, true);
+    add_wrapped_fn!(fns, plat_x86_64::rdtscp, plat_x86_64::rdtscp_calibrate, plat_x86_64::rdtscp_now, None, true);
 #[cfg(windows)]
-    add_wrapped_fn!(fns, plat_windows::qpc, plat_windows::qpc_calibrate, None, true);
+    add_wrapped_fn!(fns, plat_windows::qpc, plat_windows::qpc_calibrate, plat_windows::qpc_now, None, true);
 
 
 //    println!("iters: {}", iters.separate_with_commas());
-    println!("{:>38} {:>14} {:>12} {:>7} {:>7} {:>11} {:>7} {:>14} {:>11} {:>12}", "fnname", "clock", "nsamples", "min", "perc50", "mean", "perc95", "max", "stddev", "drift");
-    println!("{:>38} {:>14} {:>12} {:>7} {:>7} {:>11} {:>7} {:>14} {:>11} {:>12}", "------", "-----", "--------", "---", "------", "----", "------", "---", "------", "-----");
-
+    let workload = get_workload();
     let args: Vec<String> = env::args().collect();
+    let sweepsizes = sweep::get_sweep_sizes().unwrap_or_else(|e| exit_with_error(&e));
+    let numthreadsperfunc = affinity::get_threads_per_clock().unwrap_or_else(|e| exit_with_error(&e));
 
-    let numthreadsperfunc = if args.contains(&"--overthread".to_string()) {
-        let count = thread::available_parallelism().unwrap().get();
-        assert!(count >= 1_usize);
-        count * 2
-    } else {
-        1
-    };
+    let _dmalatency = lownoise::get_lownoise().and_then(|ln| {
+        println!("lownoise: measurement threads under {ln}");
+        lownoise::setup_process()
+    });
+
+    // Stopped (and joined) when this goes out of scope at the end of main().
+    let _stress = stress::start_stress();
+
+    let reference = reference::get_reference();
+    reference::check_reference().unwrap_or_else(|e| exit_with_error(&e));
+    if reference != reference::Reference::Instant {
+        println!("reference: {}", reference.name());
+    }
+
+    if overhead::get_cycles() {
+        // Worked out now rather than in the middle of the measurements.
+        overhead::calibrate_cycles(&fns).unwrap_or_else(|e| exit_with_error(&e));
+    }
 
-    for func in fns {
-        for _i in 0..numthreadsperfunc {
-            let handle = thread::spawn(func);
-            clockmeasurementhandles.push(handle);
+    if let Some(interval) = ratematrix::get_rate_matrix() {
+        if outliers::get_outliers().is_some() {
+            exit_with_error("--outliers= can't be combined with --rate-matrix, which doesn't take samples");
         }
+        ratematrix::rate_matrix(&fns, interval);
+        return;
     }
 
+    if outliers::get_outliers().is_some() && sweepsizes.is_some() {
+        exit_with_error("--outliers= can't be combined with --sweep, which only keeps each size's median");
+    }
+
+    let percpu = args.contains(&"--percpu".to_string());
+    if percpu && sweepsizes.is_some() {
+        exit_with_error("--sweep can't be combined with --percpu");
+    }
+    if percpu && significance::get_compare().is_some() {
+        exit_with_error("--compare can't be combined with --percpu, which measures each clock once per CPU");
+    }
+    let smtcpu = smt::get_smt_cpu();
+    if smtcpu.is_some() && sweepsizes.is_some() {
+        exit_with_error("--sweep can't be combined with --smt");
+    }
+    if smtcpu.is_some() && significance::get_compare().is_some() {
+        exit_with_error("--compare can't be combined with --smt, which measures each clock once per load");
+    }
+    let schedule = schedule::get_schedule();
+    if schedule == Schedule::Interleaved && sweepsizes.is_some() {
+        exit_with_error("--sweep can't be combined with --schedule=interleaved");
+    }
+
+    if let Some(sizes) = &sweepsizes {
+        println!("workload: {} sizes: {}", workload.kind.name(), sizes.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(","));
+        sweep::print_header();
+    } else {
+        if !workload.is_default() {
+            println!("workload: {workload}");
+        }
+        print_header();
+    }
+
+    let placement = affinity::get_placement();
+
     if args.contains(&"--clockjumpahead".to_string()) {
         thread::spawn(|| {
             jump_clock_ahead_thread();
         });
     }
 
-    for handle in clockmeasurementhandles {
-        handle.join().unwrap();
+    if percpu {
+        let summaries = percpu::percpu(&fns);
+        outliers::write_log(&summaries);
+        return;
+    }
+
+    if let Some(cpu) = smtcpu {
+        let summaries = smt::smt(&fns, cpu);
+        outliers::write_log(&summaries);
+        return;
+    }
+
+    if schedule == Schedule::Interleaved {
+        let summaries = schedule::interleave(&fns, numthreadsperfunc, &placement);
+        outliers::write_log(&summaries);
+        if let Some(alpha) = significance::get_compare() {
+            significance::compare(&summaries, alpha);
+        }
+        return;
+    }
+
+    // Every thread that measures at the same time as another waits at this barrier once it has
+    // calibrated, so that they all really do start together.
+    let barriersize = if schedule == Schedule::Sequential { numthreadsperfunc } else { numthreadsperfunc * fns.len() };
+    let mut barrier = Arc::new(Barrier::new(barriersize));
+
+    let mut summaries = Vec::new();
+    let mut globalidx = 0;
+    for cf in fns {
+        let mut handles = Vec::with_capacity(numthreadsperfunc);
+        for i in 0..numthreadsperfunc {
+            let sizes = sweepsizes.clone();
+            let pincpu = placement.cpu_for(&cf, i, globalidx);
+            let start = barrier.clone();
+            globalidx += 1;
+            let handle = thread::spawn(move || {
+                setup_measurement_thread(&cf.label(), pincpu);
+                match sizes {
+                    Some(sizes) => Measured::Sweep(sweep::sweep(&cf, &sizes, Some(&start))),
+                    None => Measured::Stats(Box::new(measure_recorded(&cf, Some(&start)))),
+                }
+            });
+            handles.push(handle);
+        }
+        clockmeasurementhandles.push((cf, handles));
+
+        if schedule == Schedule::Sequential {
+            summaries.extend(report_clocks(clockmeasurementhandles.drain(..), sweepsizes.as_deref()));
+            barrier = Arc::new(Barrier::new(barriersize));
+        }
+    }
+
+    summaries.extend(report_clocks(clockmeasurementhandles.drain(..), sweepsizes.as_deref()));
+    outliers::write_log(&summaries);
+
+    if let Some(alpha) = significance::get_compare() {
+        significance::compare(&summaries, alpha);
     }
 }
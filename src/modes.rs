@@ -0,0 +1,150 @@
+use thousands::Separable;
+
+use crate::histogram::Recorder;
+
+/// Below this many samples the shape of the distribution is too rough to look for modes in.
+const MIN_SAMPLES: u64 = 100;
+
+/// The narrowest the density estimate's kernel gets, in natural log units (about 1%), so that
+/// the histogram's 0.1% buckets and integer nanoseconds don't show up as modes of their own.
+const MIN_BANDWIDTH: f64 = 0.01;
+
+/// The density estimate is evaluated this many times per bandwidth.
+const STEPS_PER_BANDWIDTH: f64 = 4.0;
+
+/// Two neighbouring peaks are only separate modes if the density between them drops below this
+/// fraction of the lower one.
+const MAX_DIP: f64 = 0.5;
+
+/// A mode has to hold at least this fraction of the samples, so that a bump in the tail isn't a
+/// mode.
+const MIN_WEIGHT: f64 = 0.10;
+
+/// The density estimate only goes up to this quantile, so that a few huge outliers don't
+/// stretch it out; the samples above it still count towards the highest mode.
+const TOP_QUANTILE: f64 = 0.999;
+
+/// One cluster of samples.
+#[derive(Clone, Debug)]
+pub struct Mode {
+    /// The median of the samples in the mode.
+    pub median: u64,
+    /// The median absolute deviation of the samples in the mode from their median.
+    pub mad: u64,
+    /// The fraction of all samples that are in the mode.
+    pub weight: f64,
+}
+
+/// Describes the modes for a line under the row.
+pub fn describe(modes: &[Mode]) -> String {
+    let descs: Vec<String> = modes.iter().map(|m| format!("{}ns ({:.1}%, mad {}ns)", m.median.separate_with_commas(), m.weight * 100.0, m.mad.separate_with_commas())).collect();
+    format!("modes: {}", descs.join("; "))
+}
+
+/// Looks for modes in the recorder's samples with a kernel density estimate on a log scale,
+/// merging neighbouring peaks until every dip between them goes below `MAX_DIP` of the lower one
+/// and every mode holds at least `MIN_WEIGHT` of the samples. Returns the modes from lowest to
+/// highest, or none if there are too few samples to tell.
+pub fn find_modes(rec: &Recorder) -> Vec<Mode> {
+    if rec.count < MIN_SAMPLES {
+        return Vec::new();
+    }
+    let values: Vec<(u64, u64)> = rec.hist.iter().map(|(v, c)| (v.clamp(rec.min, rec.max), c)).collect();
+    let ln = |v: u64| (v.max(1) as f64).ln();
+
+    let n = rec.count as f64;
+    let meanlog = values.iter().map(|&(v, c)| ln(v) * c as f64).sum::<f64>() / n;
+    let sdlog = (values.iter().map(|&(v, c)| (ln(v) - meanlog).powi(2) * c as f64).sum::<f64>() / n).sqrt();
+    let iqrlog = ln(rec.quantile(0.75)) - ln(rec.quantile(0.25));
+    let spread = if iqrlog > 0.0 { sdlog.min(iqrlog / 1.34) } else { sdlog };
+    let h = (0.9 * spread * n.powf(-0.2)).max(MIN_BANDWIDTH);
+
+    let step = h / STEPS_PER_BANDWIDTH;
+    let start = ln(rec.min) - 3.0 * h;
+    let top = ln(rec.quantile(TOP_QUANTILE));
+    let len = ((top + 3.0 * h - start) / step).ceil() as usize + 1;
+    let mut density = vec![0.0; len];
+    let reach = (4.0 * STEPS_PER_BANDWIDTH) as usize;
+    for &(v, c) in &values {
+        let x = ln(v);
+        if x > top {
+            continue;
+        }
+        let centre = ((x - start) / step).round() as usize;
+        for (g, d) in density.iter_mut().enumerate().take(centre + reach + 1).skip(centre.saturating_sub(reach)) {
+            let u = (start + g as f64 * step - x) / h;
+            *d += c as f64 * (-0.5 * u * u).exp();
+        }
+    }
+
+    let mut peaks: Vec<usize> = (1..len - 1).filter(|&g| density[g] > density[g - 1] && density[g] >= density[g + 1]).collect();
+    if peaks.is_empty() {
+        return Vec::new();
+    }
+    let mut valleys: Vec<usize> = peaks.windows(2).map(|p| (p[0]..=p[1]).min_by(|&a, &b| density[a].total_cmp(&density[b])).unwrap()).collect();
+
+    // Merges the peaks either side of valley i, keeping the higher one.
+    let merge = |peaks: &mut Vec<usize>, valleys: &mut Vec<usize>, i: usize| {
+        valleys.remove(i);
+        if density[peaks[i]] < density[peaks[i + 1]] {
+            peaks.remove(i);
+        } else {
+            peaks.remove(i + 1);
+        }
+    };
+    // How shallow valley i is: its density relative to the lower of the peaks either side.
+    let shallowness = |peaks: &[usize], valleys: &[usize], i: usize| density[valleys[i]] / density[peaks[i]].min(density[peaks[i + 1]]);
+
+    while let Some(i) = (0..valleys.len()).max_by(|&a, &b| shallowness(&peaks, &valleys, a).total_cmp(&shallowness(&peaks, &valleys, b)))
+        && shallowness(&peaks, &valleys, i) > MAX_DIP
+    {
+        merge(&mut peaks, &mut valleys, i);
+    }
+
+    // Merge away modes that are too light, each into whichever neighbour it's less separated from.
+    loop {
+        let bounds: Vec<f64> = valleys.iter().map(|&g| start + g as f64 * step).collect();
+        let groups = split(&values, &bounds);
+        let Some(light) = groups.iter().position(|g| (g.iter().map(|&(_, c)| c).sum::<u64>() as f64) < MIN_WEIGHT * n) else {
+            return groups.iter().map(|g| mode_of(g, n)).collect();
+        };
+        let i = match (light.checked_sub(1), (light < valleys.len()).then_some(light)) {
+            (Some(left), Some(right)) => if shallowness(&peaks, &valleys, left) > shallowness(&peaks, &valleys, right) { left } else { right },
+            (Some(left), None) => left,
+            (None, Some(right)) => right,
+            (None, None) => return groups.iter().map(|g| mode_of(g, n)).collect(),
+        };
+        merge(&mut peaks, &mut valleys, i);
+    }
+}
+
+/// Splits the (value, count) pairs into groups at the given log-scale boundaries.
+fn split(values: &[(u64, u64)], bounds: &[f64]) -> Vec<Vec<(u64, u64)>> {
+    let mut groups = vec![Vec::new(); bounds.len() + 1];
+    for &(v, c) in values {
+        let x = (v.max(1) as f64).ln();
+        groups[bounds.iter().take_while(|&&b| x > b).count()].push((v, c));
+    }
+    groups
+}
+
+/// The median, MAD and weight of one group of (value, count) pairs, in increasing order of value.
+fn mode_of(group: &[(u64, u64)], n: f64) -> Mode {
+    let count: u64 = group.iter().map(|&(_, c)| c).sum();
+    let median = value_at_rank(group, (count.max(1) - 1) / 2);
+    let mut devs: Vec<(u64, u64)> = group.iter().map(|&(v, c)| (v.abs_diff(median), c)).collect();
+    devs.sort_unstable();
+    let mad = value_at_rank(&devs, (count.max(1) - 1) / 2);
+    Mode { median, mad, weight: count as f64 / n }
+}
+
+fn value_at_rank(sorted: &[(u64, u64)], rank: u64) -> u64 {
+    let mut seen = 0;
+    for &(v, c) in sorted {
+        seen += c;
+        if seen > rank {
+            return v;
+        }
+    }
+    0
+}
@@ -0,0 +1,267 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use thousands::Separable;
+
+use crate::budget::parse_percentile;
+use crate::calibration::Calibration;
+use crate::histogram::Recorder;
+use crate::preemption::thread_context_switches;
+use crate::workload::Workload;
+use crate::{affinity, new_recorder, run_epoch, ClockFn, Summary};
+
+const DEFAULT_LOG_PATH: &str = "outliers.log";
+
+/// How many samples a `pN` threshold is worked out from, before the measurement proper.
+const PILOT_SAMPLES: u64 = 10_000;
+
+/// The most outliers logged per measurement thread; any more are only counted, so that a low
+/// threshold can't eat all the memory.
+const MAX_LOGGED: usize = 100_000;
+
+/// /proc/interrupts is read after every outlier and, so that the interrupts charged to an outlier
+/// go back no further than this many samples, after every this many samples in between. Reading
+/// it takes far longer than a sample, so it isn't read after every one.
+const IRQ_SNAPSHOT_EVERY: u64 = 1_000;
+
+/// What counts as an outlier.
+#[derive(Clone, Copy, Debug)]
+pub enum Threshold {
+    /// Samples longer than this many nanoseconds (`--outliers=NS`).
+    Nanos(u64),
+    /// Samples longer than this quantile of a pilot run (`--outliers=pN`).
+    Quantile(f64),
+}
+
+/// Settings from `--outliers=` and `--outlier-log=`.
+#[derive(Clone, Debug)]
+pub struct OutlierSettings {
+    pub threshold: Threshold,
+    pub path: String,
+}
+
+/// Returns the settings from `--outliers=NS` or `--outliers=pN`, and `--outlier-log=PATH`
+/// (default `outliers.log` in the current directory), or None if outliers aren't to be logged.
+pub fn get_outliers() -> Option<&'static OutlierSettings> {
+    static OUTLIERS: OnceLock<Option<OutlierSettings>> = OnceLock::new();
+
+    OUTLIERS.get_or_init(|| {
+        let mut threshold = None;
+        let mut path = DEFAULT_LOG_PATH.to_string();
+        for arg in env::args() {
+            if let Some(tstr) = arg.strip_prefix("--outliers=") {
+                threshold = Some(if tstr.starts_with('p') {
+                    Threshold::Quantile(parse_percentile(tstr).unwrap_or_else(|e| panic!("{e}")))
+                } else {
+                    match tstr.parse::<u64>() {
+                        Ok(ns) => Threshold::Nanos(ns),
+                        Err(_) => panic!("--outliers= takes a threshold in nanoseconds or a percentile like p99.9, not {tstr:?}"),
+                    }
+                });
+            } else if let Some(pathstr) = arg.strip_prefix("--outlier-log=") {
+                path = pathstr.to_string();
+            }
+        }
+        threshold.map(|threshold| OutlierSettings { threshold, path })
+    }).as_ref()
+}
+
+/// One sample above the threshold, and what was going on around it.
+pub struct Outlier {
+    pub value: u64,
+    /// The threshold it was above, which with `--outliers=pN` differs from thread to thread.
+    pub threshold: u64,
+    /// When the sample started, as wall-clock time since the Unix epoch.
+    pub realtime: Duration,
+    /// When the sample started, relative to `run_epoch()`, like the startus column.
+    pub since_start: Duration,
+    /// The CPU(s) the sample ran on, see `affinity::format_cpus()`.
+    pub cpus: String,
+    /// The thread's voluntary and involuntary context switches during the sample.
+    pub voluntary: u64,
+    pub involuntary: u64,
+    /// The interrupts that fired on the sample's CPU(s), by name, or None if /proc/interrupts
+    /// couldn't be read.
+    pub irqs: Option<Vec<(String, u64)>>,
+    /// How many samples, ending with this one, `irqs` were counted over.
+    pub irqsamples: u64,
+}
+
+/// One measurement thread's outliers, or several threads' merged.
+pub struct Outliers {
+    /// The threshold in nanoseconds, like the recorded samples it's compared with (scaled clocks'
+    /// ticks are converted as they're recorded), or the lowest of them once several threads'
+    /// outliers are merged.
+    pub threshold: u64,
+    pub logged: Vec<Outlier>,
+    /// How many more there were than `MAX_LOGGED`.
+    pub unlogged: u64,
+    /// The interrupt counts as of the last snapshot, and how many samples have been taken since.
+    irqs: Option<Interrupts>,
+    sincesnapshot: u64,
+}
+
+impl Outliers {
+    /// Works out the threshold, taking a pilot run of the clock first if it's a percentile.
+    pub fn new(cf: &ClockFn, workload: &Workload, calibration: &Calibration, settings: &OutlierSettings) -> Outliers {
+        let threshold = match settings.threshold {
+            Threshold::Nanos(ns) => ns,
+            Threshold::Quantile(q) => {
+                let mut pilot = new_recorder(cf, calibration);
+                (cf.func)(cf.clock, PILOT_SAMPLES, workload, &mut pilot);
+                pilot.quantile(q)
+            }
+        };
+        Outliers { threshold, logged: Vec::new(), unlogged: 0, irqs: Interrupts::read(), sincesnapshot: 0 }
+    }
+
+    /// Takes `n` samples one at a time by calling `sample(1, rec)`, noting the time, CPU and
+    /// context switches around each one, and logs the ones above the threshold. The interrupts
+    /// charged to an outlier are the ones since the previous snapshot of /proc/interrupts (see
+    /// `IRQ_SNAPSHOT_EVERY`), which includes the bookkeeping between samples and maybe some earlier
+    /// samples, but never an earlier outlier.
+    pub fn take_logged(&mut self, n: u64, rec: &mut Recorder, mut sample: impl FnMut(u64, &mut Recorder)) {
+        for _ in 0..n {
+            let before = rec.count;
+            let cpu1 = affinity::current_cpu();
+            let (voluntary1, involuntary1) = thread_context_switches();
+            let realtime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            let since_start = run_epoch().elapsed();
+            sample(1, rec);
+            let (voluntary2, involuntary2) = thread_context_switches();
+            let cpu2 = affinity::current_cpu();
+            self.sincesnapshot += 1;
+
+            let outlier = rec.count > before && rec.last > self.threshold;
+            let logging = outlier && self.logged.len() < MAX_LOGGED;
+            if outlier && !logging {
+                self.unlogged += 1;
+            }
+            if !logging && self.sincesnapshot < IRQ_SNAPSHOT_EVERY {
+                continue;
+            }
+
+            let irqs = Interrupts::read();
+            if logging {
+                let fired = match (&self.irqs, &irqs) {
+                    (Some(a), Some(b)) => Some(b.fired_since(a, cpu1, cpu2)),
+                    _ => None,
+                };
+                self.logged.push(Outlier { value: rec.last, threshold: self.threshold, realtime, since_start, cpus: affinity::format_cpus(cpu1, cpu2), voluntary: voluntary2 - voluntary1, involuntary: involuntary2 - involuntary1, irqs: fired, irqsamples: self.sincesnapshot });
+            }
+            self.irqs = irqs;
+            self.sincesnapshot = 0;
+        }
+    }
+
+    /// Adds another thread's outliers to these, keeping them in the order they happened.
+    pub fn merge(&mut self, other: Outliers) {
+        self.threshold = self.threshold.min(other.threshold);
+        self.logged.extend(other.logged);
+        self.logged.sort_by_key(|o| o.since_start);
+        self.unlogged += other.unlogged;
+    }
+
+    /// Describes the outliers for a line under the row.
+    pub fn describe(&self, path: &str) -> String {
+        let mut desc = format!("outliers: {} above {} logged to {path}", self.logged.len().separate_with_commas(), self.threshold.separate_with_commas());
+        if self.unlogged > 0 {
+            desc.push_str(&format!(" ({} more not logged)", self.unlogged.separate_with_commas()));
+        }
+        desc
+    }
+}
+
+/// Writes every summary's outliers to the `--outlier-log=` file, one line each, in the order the
+/// summaries are in.
+pub fn write_log(summaries: &[Summary]) {
+    let Some(settings) = get_outliers() else {
+        return;
+    };
+
+    let file = File::create(&settings.path).unwrap_or_else(|e| panic!("--outlier-log={}: {e}", settings.path));
+    let mut out = BufWriter::new(file);
+    let write = |out: &mut BufWriter<File>| -> std::io::Result<()> {
+        writeln!(out, "# fnname clock threshold value realtime startus cpu voluntary_cs involuntary_cs interrupts_over_samples interrupts")?;
+        for s in summaries {
+            let Some(outliers) = &s.outliers else {
+                continue;
+            };
+            for o in &outliers.logged {
+                let irqs = match &o.irqs {
+                    Some(irqs) if irqs.is_empty() => "-".to_string(),
+                    Some(irqs) => irqs.iter().map(|(name, n)| format!("{name}+{n}")).collect::<Vec<_>>().join(","),
+                    None => "?".to_string(),
+                };
+                writeln!(out, "{} {} {} {} {}.{:09} {} {} {} {} {} {irqs}", s.fnname, s.clockname, o.threshold, o.value, o.realtime.as_secs(), o.realtime.subsec_nanos(), o.since_start.as_micros(), o.cpus, o.voluntary, o.involuntary, o.irqsamples)?;
+            }
+        }
+        out.flush()
+    };
+    write(&mut out).unwrap_or_else(|e| panic!("--outlier-log={}: {e}", settings.path));
+}
+
+/// A snapshot of /proc/interrupts.
+struct Interrupts {
+    /// The CPU number of each column.
+    cpus: Vec<usize>,
+    /// Each interrupt's name and its count in each column. Numbered interrupts are named after
+    /// their device too, e.g. `24(eth0)`.
+    lines: Vec<(String, Vec<u64>)>,
+}
+
+impl Interrupts {
+    fn read() -> Option<Interrupts> {
+        let text = fs::read_to_string("/proc/interrupts").ok()?;
+        let mut textlines = text.lines();
+        let cpus: Vec<usize> = textlines.next()?.split_whitespace().filter_map(|c| c.strip_prefix("CPU")?.parse().ok()).collect();
+
+        let mut lines = Vec::new();
+        for line in textlines {
+            let Some((name, rest)) = line.split_once(':') else {
+                continue;
+            };
+            let name = name.trim();
+            let mut fields = rest.split_whitespace().peekable();
+            let mut counts = Vec::with_capacity(cpus.len());
+            while counts.len() < cpus.len()
+                && let Some(count) = fields.peek().and_then(|f| f.parse::<u64>().ok())
+            {
+                counts.push(count);
+                fields.next();
+            }
+            let name = match (name.chars().all(|c| c.is_ascii_digit()), fields.last()) {
+                (true, Some(device)) => format!("{name}({device})"),
+                _ => name.to_string(),
+            };
+            lines.push((name, counts));
+        }
+        Some(Interrupts { cpus, lines })
+    }
+
+    /// The interrupts whose counts went up since `earlier` on the CPU(s) the sample ran on, or on
+    /// any CPU if we don't know which.
+    fn fired_since(&self, earlier: &Interrupts, cpu1: Option<usize>, cpu2: Option<usize>) -> Vec<(String, u64)> {
+        let columns: Vec<usize> = match (cpu1, cpu2) {
+            (Some(c1), Some(c2)) => (0..self.cpus.len()).filter(|&i| self.cpus[i] == c1 || self.cpus[i] == c2).collect(),
+            _ => (0..self.cpus.len()).collect(),
+        };
+
+        let mut fired = Vec::new();
+        for (i, (name, counts)) in self.lines.iter().enumerate() {
+            // Interrupts come and go as devices do, so only trust the line at the same index if
+            // it's the same interrupt.
+            let Some((_, earliercounts)) = earlier.lines.get(i).filter(|(n, _)| n == name).or_else(|| earlier.lines.iter().find(|(n, _)| n == name)) else {
+                continue;
+            };
+            let delta: u64 = columns.iter().map(|&c| counts.get(c).unwrap_or(&0).saturating_sub(*earliercounts.get(c).unwrap_or(&0))).sum();
+            if delta > 0 {
+                fired.push((name.clone(), delta));
+            }
+        }
+        fired
+    }
+}
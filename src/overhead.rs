@@ -0,0 +1,56 @@
+use std::env;
+use std::sync::OnceLock;
+use crate::calibration::{self, Calibration};
+use crate::workload::{Workload, WorkloadKind};
+use crate::{new_recorder, ClockFn};
+
+/// How many back-to-back reads `--subtract-overhead` takes to find a clock's overhead.
+const OVERHEAD_SAMPLES: u64 = 10_000;
+
+/// The `fnname` of the clock that reads the TSC, whose calibration gives `--cycles` its rate.
+const TSC_FNNAME: &str = "plat_x86_64::rdtscp";
+
+/// The TSC's ticks per nanosecond, once `calibrate_cycles()` has worked it out.
+static TSC_TICKS_PER_NS: OnceLock<f64> = OnceLock::new();
+
+/// Returns whether `--subtract-overhead` was given.
+pub fn get_subtract_overhead() -> bool {
+    static SUBTRACT: OnceLock<bool> = OnceLock::new();
+    *SUBTRACT.get_or_init(|| env::args().any(|arg| arg == "--subtract-overhead"))
+}
+
+/// Returns whether `--cycles` was given.
+pub fn get_cycles() -> bool {
+    static CYCLES: OnceLock<bool> = OnceLock::new();
+    *CYCLES.get_or_init(|| env::args().any(|arg| arg == "--cycles"))
+}
+
+/// Measures the clock's own overhead: the median time between two back-to-back reads with nothing
+/// in between, in the same units as its samples. Reads that came out zero aren't recorded, but
+/// they're part of the overhead too, so they count as the lowest samples.
+pub fn measure_overhead(cf: &ClockFn, calibration: &Calibration) -> u64 {
+    let mut rec = new_recorder(cf, calibration);
+    (cf.func)(cf.clock, OVERHEAD_SAMPLES, &Workload::new(WorkloadKind::Empty, 0), &mut rec);
+    if rec.skipped >= rec.count {
+        return 0;
+    }
+    let rank = (rec.count + rec.skipped - 1) as f64 / 2.0 - rec.skipped as f64;
+    rec.quantile(rank / (rec.count - 1).max(1) as f64)
+}
+
+/// Works out the TSC's ticks per nanosecond for `--cycles`, by calibrating the clock that reads
+/// it against the reference like any other, or returns an error if there's no such clock here.
+/// The TSC runs at a constant rate, which is the CPU's nominal frequency rather than whatever it
+/// happens to be clocked at right now.
+pub fn calibrate_cycles(fns: &[ClockFn]) -> Result<(), String> {
+    let Some(cf) = fns.iter().find(|cf| cf.fnname == TSC_FNNAME) else {
+        return Err("--cycles needs the TSC, which is only read on x86_64".to_string());
+    };
+    TSC_TICKS_PER_NS.get_or_init(|| calibration::calibrate(cf).ratio());
+    Ok(())
+}
+
+/// Returns the TSC's ticks per nanosecond, for expressing durations in cycles.
+pub fn tsc_ticks_per_ns() -> f64 {
+    *TSC_TICKS_PER_NS.get().expect("--cycles: the TSC hasn't been calibrated")
+}
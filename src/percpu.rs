@@ -0,0 +1,47 @@
+use std::thread;
+
+use thousands::Separable;
+
+use crate::{affinity, measure, print_row, setup_measurement_thread, ClockFn, Summary};
+
+/// `--percpu`: measures every clock pinned to each allowed CPU in turn, one measurement at a time
+/// so that they don't disturb each other, then prints how much each clock's results vary between
+/// CPUs. Returns every measurement's summary, clock by clock.
+pub fn percpu(fns: &[ClockFn]) -> Vec<Summary> {
+    let cpus = affinity::online_cpus();
+
+    // results[i] is every CPU's summary for fns[i]
+    let mut results: Vec<Vec<(usize, Summary)>> = fns.iter().map(|_| Vec::with_capacity(cpus.len())).collect();
+
+    for &cpu in &cpus {
+        for (i, cf) in fns.iter().enumerate() {
+            let cf = *cf;
+            let summary = thread::spawn(move || {
+                setup_measurement_thread(&cf.label(), Some(cpu));
+                measure(&cf, None)
+            }).join().unwrap();
+
+            print_row(&summary);
+            results[i].push((cpu, summary));
+        }
+    }
+
+    println!();
+    println!("{:>38} {:>14} {:>5} {:>7} {:>7} {:>7} {:>7} {:>11} {:>11} {:>7}", "fnname", "clock", "ncpus", "p50lo", "p50hi", "p95lo", "p95hi", "stddevlo", "stddevhi", "worst");
+    println!("{:>38} {:>14} {:>5} {:>7} {:>7} {:>7} {:>7} {:>11} {:>11} {:>7}", "------", "-----", "-----", "-----", "-----", "-----", "-----", "--------", "--------", "-----");
+
+    for (cf, percpu) in fns.iter().zip(&results) {
+        let p50lo = percpu.iter().map(|(_, s)| s.perc50).min().unwrap();
+        let p50hi = percpu.iter().map(|(_, s)| s.perc50).max().unwrap();
+        let p95lo = percpu.iter().map(|(_, s)| s.perc95).min().unwrap();
+        let p95hi = percpu.iter().map(|(_, s)| s.perc95).max().unwrap();
+        let sdlo = percpu.iter().map(|(_, s)| s.stddev as u128).min().unwrap();
+        let sdhi = percpu.iter().map(|(_, s)| s.stddev as u128).max().unwrap();
+        // The CPU with the worst tail is the one most worth looking into.
+        let (worstcpu, _) = percpu.iter().max_by_key(|(_, s)| s.perc95).unwrap();
+
+        println!("{:>38} {:>14} {:>5} {:>7} {:>7} {:>7} {:>7} {:>11} {:>11} {:>7}", cf.fnname, cf.clockname, percpu.len(), p50lo.separate_with_commas(), p50hi.separate_with_commas(), p95lo.separate_with_commas(), p95hi.separate_with_commas(), sdlo.separate_with_commas(), sdhi.separate_with_commas(), format!("cpu{worstcpu}"));
+    }
+
+    results.into_iter().flatten().map(|(_, s)| s).collect()
+}
@@ -0,0 +1,201 @@
+use std::env;
+use std::f64::consts::PI;
+use std::sync::OnceLock;
+
+use thousands::Separable;
+
+/// How many samples `--periodicity` keeps in order per measurement thread, if not given: 512KiB
+/// worth, which finds periods of up to 16,384 samples.
+const DEFAULT_SERIES_LIMIT: usize = 1 << 16;
+
+/// A period has to fit this many times into the series to be looked for.
+const MIN_CYCLES: usize = 4;
+
+/// How many of the strongest periods get reported.
+const MAX_PERIODS: usize = 3;
+
+/// Below this many samples there's nothing to be found.
+const MIN_SAMPLES: usize = 64;
+
+/// Durations are clipped to this quantile of themselves before they're correlated, so that a
+/// couple of huge one-off spikes that happen to be some distance apart can't pass for a period.
+const CLIP_QUANTILE: f64 = 0.999;
+
+/// A peak at lag k is measured against the lags within k / `BASELINE_FRACTION` of it (but at
+/// least `MIN_BASELINE` either side), leaving out its immediate neighbours.
+const BASELINE_FRACTION: usize = 4;
+const MIN_BASELINE: usize = 3;
+
+/// Peaks this close (relative to the lag) to a multiple of a stronger period count as its
+/// harmonics rather than periods of their own.
+const HARMONIC_TOLERANCE: f64 = 0.02;
+
+/// Returns how many samples to keep in order from `--periodicity` or `--periodicity=N`, or None
+/// if they aren't to be looked at.
+pub fn get_periodicity() -> Option<usize> {
+    static PERIODICITY: OnceLock<Option<usize>> = OnceLock::new();
+
+    *PERIODICITY.get_or_init(|| {
+        for arg in env::args() {
+            if arg == "--periodicity" {
+                return Some(DEFAULT_SERIES_LIMIT);
+            }
+            if let Some(nstr) = arg.strip_prefix("--periodicity=") {
+                return match nstr.parse::<usize>() {
+                    Ok(n) if n >= MIN_SAMPLES => Some(n),
+                    _ => panic!("--periodicity= takes how many samples to keep, at least {MIN_SAMPLES}, not {nstr:?}"),
+                };
+            }
+        }
+        None
+    })
+}
+
+/// A lag at which the samples' durations correlate with themselves.
+#[derive(Clone, Debug)]
+pub struct Period {
+    /// The lag, in samples.
+    pub samples: usize,
+    /// The lag in nanoseconds, going by the average time from one sample to the next.
+    pub nanos: f64,
+    /// How far the autocorrelation at the lag stands above that of the lags around it.
+    pub correlation: f64,
+    /// How high a spike every `samples` samples would have to be to account for the covariance at
+    /// the lag over that of the lags around it (and the lags either side of it, which a period
+    /// that isn't a whole number of samples spreads over), in nanoseconds. Being worked out from
+    /// the clipped durations, it can't come out much higher than their `CLIP_QUANTILE`.
+    pub spike: f64,
+}
+
+/// What `find_periods()` found.
+#[derive(Clone, Debug)]
+pub struct Periodicity {
+    /// How many samples were looked at.
+    pub samples: usize,
+    /// The strongest periods first.
+    pub periods: Vec<Period>,
+}
+
+impl Periodicity {
+    /// Describes the periods for a line under the row.
+    pub fn describe(&self) -> String {
+        if self.periods.is_empty() {
+            return format!("periods: none in {} samples", self.samples.separate_with_commas());
+        }
+        let periods: Vec<String> = self.periods.iter().map(|p| format!("every {} samples (~{}us): r {:.3}, spike ~{}ns", p.samples.separate_with_commas(), ((p.nanos / 1000.0).round() as u64).separate_with_commas(), p.correlation, (p.spike.round() as u64).separate_with_commas())).collect();
+        format!("periods: {}", periods.join("; "))
+    }
+}
+
+/// Looks for periodic patterns in the durations of consecutive samples, `spacing` nanoseconds
+/// apart on average, by their autocorrelation (via an FFT) at lags up to a quarter of the series.
+/// Returns the lags where it peaks above the neighbouring lags by more than noise would with 99%
+/// probability (by Bartlett's standard errors), strongest first, leaving out harmonics.
+pub fn find_periods(series: &[u64], spacing: f64) -> Periodicity {
+    let n = series.len();
+    let maxlag = n / MIN_CYCLES;
+    if n < MIN_SAMPLES {
+        return Periodicity { samples: n, periods: Vec::new() };
+    }
+
+    let mut sorted = series.to_vec();
+    sorted.sort_unstable();
+    let clip = sorted[((n - 1) as f64 * CLIP_QUANTILE) as usize] as f64;
+    let clipped: Vec<f64> = series.iter().map(|&v| (v as f64).min(clip)).collect();
+
+    let mean = clipped.iter().sum::<f64>() / n as f64;
+    let size = (2 * n).next_power_of_two();
+    let mut re: Vec<f64> = clipped.iter().map(|v| v - mean).chain(std::iter::repeat(0.0)).take(size).collect();
+    let mut im = vec![0.0; size];
+    fft(&mut re, &mut im, false);
+    for (r, i) in re.iter_mut().zip(im.iter_mut()) {
+        *r = *r * *r + *i * *i;
+        *i = 0.0;
+    }
+    fft(&mut re, &mut im, true);
+
+    // re[k] is now size times the sum of the products of the deviations k apart.
+    let variance = re[0] / size as f64 / n as f64;
+    if variance == 0.0 {
+        return Periodicity { samples: n, periods: Vec::new() };
+    }
+    let acf: Vec<f64> = re[..n].iter().map(|c| c / re[0]).collect();
+    let z = (2.0 * (100.0 * maxlag as f64).ln()).sqrt();
+    let mut sumsq = 0.0;
+    let stderrs: Vec<f64> = acf.iter().enumerate().map(|(k, r)| {
+        let stderr = ((1.0 + 2.0 * sumsq) / n as f64).sqrt();
+        if k > 0 {
+            sumsq += r * r;
+        }
+        stderr
+    }).collect();
+
+    // Lag 1 is left out: that's samples being like their neighbours, not a period.
+    let prominence = |k: usize| {
+        let w = (k / BASELINE_FRACTION).max(MIN_BASELINE);
+        let around: Vec<f64> = (k.saturating_sub(w).max(1)..=(k + w).min(n - 1)).filter(|&j| j.abs_diff(k) > 1).map(|j| acf[j]).collect();
+        acf[k] - around.iter().sum::<f64>() / around.len() as f64
+    };
+    let mut peaks: Vec<(usize, f64)> = (2..=maxlag).filter(|&k| acf[k] > acf[k - 1] && acf[k] >= acf[k + 1]).map(|k| (k, prominence(k))).filter(|&(k, p)| p > z * stderrs[k]).collect();
+    peaks.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    let mut periods: Vec<Period> = Vec::new();
+    for (k, prom) in peaks {
+        let harmonic = periods.iter().any(|p| {
+            let multiple = (k as f64 / p.samples as f64).round();
+            multiple >= 1.0 && (k as f64 - multiple * p.samples as f64).abs() <= (HARMONIC_TOLERANCE * k as f64).max(1.0)
+        });
+        if harmonic {
+            continue;
+        }
+        let baseline = acf[k] - prom;
+        let covariance: f64 = acf[k - 1..=k + 1].iter().map(|r| (r - baseline).max(0.0) * variance).sum();
+        periods.push(Period { samples: k, nanos: k as f64 * spacing, correlation: prom, spike: (k as f64 * covariance).sqrt() });
+        if periods.len() == MAX_PERIODS {
+            break;
+        }
+    }
+    Periodicity { samples: n, periods }
+}
+
+/// An in-place iterative radix-2 FFT (or unscaled inverse FFT) of `re` + i`im`, whose length must
+/// be a power of two.
+fn fft(re: &mut [f64], im: &mut [f64], inverse: bool) {
+    let n = re.len();
+
+    // Put the elements in bit-reversed order of their indices.
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as f64;
+        let (wre, wim) = (angle.cos(), angle.sin());
+        for start in (0..n).step_by(len) {
+            let (mut cre, mut cim) = (1.0, 0.0);
+            for a in start..start + len / 2 {
+                let b = a + len / 2;
+                let tre = re[b] * cre - im[b] * cim;
+                let tim = re[b] * cim + im[b] * cre;
+                re[b] = re[a] - tre;
+                im[b] = im[a] - tim;
+                re[a] += tre;
+                im[a] += tim;
+                (cre, cim) = (cre * wre - cim * wim, cre * wim + cim * wre);
+            }
+        }
+        len <<= 1;
+    }
+}
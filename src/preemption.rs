@@ -0,0 +1,133 @@
+use std::env;
+use std::sync::OnceLock;
+
+use thousands::Separable;
+
+use crate::histogram::Recorder;
+use crate::workload::Workload;
+use crate::ClockFn;
+
+/// How much longer than the thread's CPU time a sample may take before it counts as preempted.
+const DEFAULT_SLACK_NS: u64 = 1_000;
+
+/// Returns the slack from `--preemption` or `--preemption=NS`, or None if samples aren't to be
+/// classified.
+pub fn get_preemption() -> Option<u64> {
+    static PREEMPTION: OnceLock<Option<u64>> = OnceLock::new();
+
+    *PREEMPTION.get_or_init(|| {
+        for arg in env::args() {
+            if arg == "--preemption" {
+                return Some(DEFAULT_SLACK_NS);
+            }
+            if let Some(slackstr) = arg.strip_prefix("--preemption=") {
+                return match slackstr.parse::<u64>() {
+                    Ok(slack) => Some(slack),
+                    Err(_) => panic!("--preemption= takes a slack in nanoseconds, not {slackstr:?}"),
+                };
+            }
+        }
+        None
+    })
+}
+
+/// The samples split by whether the thread was off the CPU during them.
+pub struct Classified {
+    /// Samples during which the thread's CPU time kept up with the clock: it ran uninterrupted
+    /// (apart from interrupts, which the kernel mostly charges to whichever thread they land on).
+    pub uninterrupted: Recorder,
+    /// Samples that took more than the slack longer than the thread's CPU time advanced.
+    pub preempted: Recorder,
+    /// How many of the preempted samples saw a context switch. (Samples that saw one but didn't
+    /// lose any time to it are left uninterrupted: the switch happened around the sample rather
+    /// than in it.)
+    pub switched: u64,
+}
+
+impl Classified {
+    pub fn new() -> Classified {
+        Classified { uninterrupted: Recorder::new(None), preempted: Recorder::new(None), switched: 0 }
+    }
+
+    /// Adds another thread's classes to these.
+    pub fn merge(&mut self, other: &Classified) {
+        self.uninterrupted.merge(&other.uninterrupted);
+        self.preempted.merge(&other.preempted);
+        self.switched += other.switched;
+    }
+}
+
+/// Takes `n` samples one at a time, reading the thread's CPU time and context switch count around
+/// each one, recording each sample in `rec` and also in the matching class.
+pub fn take_classified(cf: &ClockFn, workload: &Workload, n: u64, slack: u64, rec: &mut Recorder, classes: &mut Classified) {
+    for _ in 0..n {
+        let before = rec.count;
+        let switches1 = context_switches();
+        let cpu1 = thread_cpu_ns();
+        (cf.func)(cf.clock, 1, workload, rec);
+        let cpu2 = thread_cpu_ns();
+        let switches2 = context_switches();
+        if rec.count == before {
+            continue;
+        }
+
+        let v = rec.last;
+        if v > (cpu2 - cpu1) + slack {
+            classes.preempted.record(v);
+            classes.switched += (switches2 != switches1) as u64;
+        } else {
+            classes.uninterrupted.record(v);
+        }
+    }
+}
+
+/// Formats the statistics of one class for a line under the row.
+pub fn format_class(name: &str, rec: &Recorder) -> String {
+    if rec.count == 0 {
+        return format!("{name}: none");
+    }
+    format!("{name}: n {} min {} perc50 {} mean {} perc95 {} max {} stddev {}ns", rec.count.separate_with_commas(), rec.min.separate_with_commas(), rec.quantile(0.5).separate_with_commas(), (rec.mean.round() as u64).separate_with_commas(), rec.quantile(0.95).separate_with_commas(), rec.max.separate_with_commas(), (rec.stddev() as u128).separate_with_commas())
+}
+
+#[cfg(unix)]
+fn thread_cpu_ns() -> u64 {
+    use crate::plat_unixes::libc;
+    use std::mem::MaybeUninit;
+
+    let mut tp: MaybeUninit<libc::timespec> = MaybeUninit::uninit();
+    let retval = unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, tp.as_mut_ptr()) };
+    assert_eq!(retval, 0);
+    let tp = unsafe { tp.assume_init() };
+    tp.tv_sec as u64 * 1_000_000_000 + tp.tv_nsec as u64
+}
+
+#[cfg(not(unix))]
+fn thread_cpu_ns() -> u64 {
+    panic!("--preemption is only implemented on unixes");
+}
+
+/// The calling thread's voluntary plus involuntary context switches so far, or 0 where we can't
+/// find out, in which case only CPU time is used.
+fn context_switches() -> u64 {
+    let (voluntary, involuntary) = thread_context_switches();
+    voluntary + involuntary
+}
+
+/// The calling thread's (voluntary, involuntary) context switches so far, or (0, 0) where we
+/// can't find out.
+#[cfg(target_os = "linux")]
+pub fn thread_context_switches() -> (u64, u64) {
+    use crate::plat_unixes::libc;
+    use std::mem::MaybeUninit;
+
+    let mut ru: MaybeUninit<libc::rusage> = MaybeUninit::uninit();
+    let retval = unsafe { libc::getrusage(libc::RUSAGE_THREAD, ru.as_mut_ptr()) };
+    assert_eq!(retval, 0);
+    let ru = unsafe { ru.assume_init() };
+    (ru.ru_nvcsw as u64, ru.ru_nivcsw as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn thread_context_switches() -> (u64, u64) {
+    (0, 0)
+}
@@ -0,0 +1,75 @@
+use std::env;
+use std::hint::spin_loop;
+use std::time::{Duration, Instant};
+
+use crate::budget::parse_duration;
+use crate::reference::{self, Reference};
+use crate::ClockFn;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Returns the interval from `--rate-matrix` (100ms) or `--rate-matrix=T`, if given.
+pub fn get_rate_matrix() -> Option<Duration> {
+    for arg in env::args() {
+        if arg == "--rate-matrix" {
+            return Some(DEFAULT_INTERVAL);
+        }
+        if let Some(rmstr) = arg.strip_prefix("--rate-matrix=") {
+            return Some(parse_duration(rmstr).unwrap_or_else(|e| panic!("{e}")));
+        }
+    }
+    None
+}
+
+/// Reads every clock, spins for `interval`, reads every clock again (in the same order, so that
+/// the time between reads mostly cancels out), and prints how far each clock advanced per unit of
+/// every other clock. Spinning rather than sleeping keeps CPU-time clocks comparable with the
+/// rest; they'll still come out a little slow by however much of the interval the thread spent
+/// off the CPU. Clocks that count ticks are compared in ticks. A `--reference=` other than
+/// `Instant` gets a row and column of its own, in its own units.
+pub fn rate_matrix(fns: &[ClockFn], interval: Duration) {
+    let reference = reference::get_reference();
+    let withref = reference != Reference::Instant;
+    let read_all = || {
+        let mut reads: Vec<u64> = fns.iter().map(|cf| (cf.now)(cf.clock)).collect();
+        if withref {
+            reads.push(reference::read(reference));
+        }
+        reads
+    };
+
+    let starts = read_all();
+    let start = Instant::now();
+    while start.elapsed() < interval {
+        spin_loop();
+    }
+    let ends = read_all();
+    let deltas: Vec<f64> = starts.iter().zip(&ends).map(|(s, e)| e.saturating_sub(*s) as f64).collect();
+
+    println!("rate matrix over {interval:?}: how far the row's clock advanced per unit its column's advanced");
+    for (i, cf) in fns.iter().enumerate() {
+        let unit = if cf.scale { " (ticks)" } else { "" };
+        println!("[{}] {}{unit}", i + 1, cf.label());
+    }
+    if withref {
+        let unit = if reference.ticks() { " (ticks)" } else { "" };
+        println!("[{}] reference {}{unit}", fns.len() + 1, reference.name());
+    }
+
+    let mut header = format!("{:>5}", "");
+    for i in 0..deltas.len() {
+        header.push_str(&format!(" {:>12}", format!("[{}]", i + 1)));
+    }
+    println!("{header}");
+    for (i, di) in deltas.iter().enumerate() {
+        let mut row = format!("{:>5}", format!("[{}]", i + 1));
+        for dj in &deltas {
+            if *dj == 0.0 {
+                row.push_str(&format!(" {:>12}", "---"));
+            } else {
+                row.push_str(&format!(" {:>12.6}", di / dj));
+            }
+        }
+        println!("{row}");
+    }
+}
@@ -0,0 +1,197 @@
+use std::env;
+use std::hint::spin_loop;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::run_epoch;
+
+/// How long to spin when working out the rate of a reference that counts ticks or cycles.
+const TICK_RATE_TIME: Duration = Duration::from_millis(100);
+
+/// The clock every other clock is calibrated against, and whose rate the drift column is relative
+/// to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reference {
+    /// `std::time::Instant` (the default), which is `CLOCK_MONOTONIC` on Linux.
+    Instant,
+    /// `CLOCK_MONOTONIC_RAW`, which NTP doesn't slew.
+    MonotonicRaw,
+    /// `CLOCK_BOOTTIME`, which keeps counting during suspend.
+    Boottime,
+    /// The TSC, read with `rdtscp`.
+    Tsc,
+    /// The thread's own CPU cycles, from a perf hardware counter. It stops while the thread is off
+    /// the CPU and follows the core's frequency, so calibration spins under it (see
+    /// `calibration::spins()`) and its drift shows how the frequency changed.
+    Cycles,
+}
+
+impl Reference {
+    pub const ALL: [Reference; 5] = [Reference::Instant, Reference::MonotonicRaw, Reference::Boottime, Reference::Tsc, Reference::Cycles];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Reference::Instant => "instant",
+            Reference::MonotonicRaw => "monotonic_raw",
+            Reference::Boottime => "boottime",
+            Reference::Tsc => "tsc",
+            Reference::Cycles => "cycles",
+        }
+    }
+
+    /// Whether this reference counts ticks that have to be converted to nanoseconds.
+    pub fn ticks(self) -> bool {
+        matches!(self, Reference::Tsc | Reference::Cycles)
+    }
+}
+
+/// Returns the reference clock from `--reference=NAME`, `Instant` if not given.
+pub fn get_reference() -> Reference {
+    static REFERENCE: OnceLock<Reference> = OnceLock::new();
+
+    *REFERENCE.get_or_init(|| {
+        for arg in env::args() {
+            if let Some(refstr) = arg.strip_prefix("--reference=") {
+                return Reference::ALL.into_iter().find(|r| r.name() == refstr).unwrap_or_else(|| {
+                    let names: Vec<&str> = Reference::ALL.iter().map(|r| r.name()).collect();
+                    panic!("unknown --reference= {refstr:?}, expected one of: {}", names.join(", "))
+                });
+            }
+        }
+        Reference::Instant
+    })
+}
+
+/// Checks that the reference can be read here, so that main() can say why not before any thread
+/// tries to.
+pub fn check_reference() -> Result<(), String> {
+    match get_reference() {
+        Reference::Cycles => open_cycles().map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+/// Reads the reference clock, in nanoseconds since some arbitrary point. References that count
+/// ticks are converted at a rate worked out (against `CLOCK_MONOTONIC_RAW`, while spinning) the
+/// first time they're read, so their nanoseconds are only as good as that. The TSC's rate is the
+/// same everywhere, so it's worked out once; cycles are counted per thread, at whatever frequency
+/// its core runs at, so their rate is worked out once per thread.
+pub fn now() -> u64 {
+    static TSC_TICKS_PER_NS: OnceLock<f64> = OnceLock::new();
+    thread_local! {
+        static CYCLES_PER_NS: f64 = tick_rate(Reference::Cycles);
+    }
+
+    let reference = get_reference();
+    let rate = match reference {
+        Reference::Tsc => *TSC_TICKS_PER_NS.get_or_init(|| tick_rate(reference)),
+        Reference::Cycles => CYCLES_PER_NS.with(|rate| *rate),
+        _ => return read(reference),
+    };
+    (read(reference) as f64 / rate) as u64
+}
+
+/// Works out a reference's ticks per nanosecond by spinning for `TICK_RATE_TIME`.
+fn tick_rate(reference: Reference) -> f64 {
+    let (t1, n1) = (read(reference), read(Reference::MonotonicRaw));
+    let start = Instant::now();
+    while start.elapsed() < TICK_RATE_TIME {
+        spin_loop();
+    }
+    let (t2, n2) = (read(reference), read(Reference::MonotonicRaw));
+    (t2 - t1) as f64 / (n2 - n1) as f64
+}
+
+/// Reads the reference in its own units.
+pub fn read(reference: Reference) -> u64 {
+    match reference {
+        Reference::Instant => run_epoch().elapsed().as_nanos() as u64,
+        Reference::MonotonicRaw | Reference::Boottime => read_clock_gettime(reference),
+        Reference::Tsc => read_tsc(),
+        Reference::Cycles => read_cycles(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn read_clock_gettime(reference: Reference) -> u64 {
+    use crate::plat_unixes::libc;
+    use std::mem::MaybeUninit;
+
+    let ct = match reference {
+        Reference::MonotonicRaw => libc::CLOCK_MONOTONIC_RAW,
+        _ => libc::CLOCK_BOOTTIME,
+    };
+    let mut tp: MaybeUninit<libc::timespec> = MaybeUninit::uninit();
+    let retval = unsafe { libc::clock_gettime(ct, tp.as_mut_ptr()) };
+    assert_eq!(retval, 0, "clock_gettime: {}", std::io::Error::last_os_error());
+    let tp = unsafe { tp.assume_init() };
+    tp.tv_sec as u64 * 1_000_000_000 + tp.tv_nsec as u64
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_clock_gettime(reference: Reference) -> u64 {
+    panic!("--reference={} is only implemented on Linux", reference.name());
+}
+
+#[cfg(target_arch = "x86_64")]
+fn read_tsc() -> u64 {
+    let mut aux = 0;
+    unsafe { core::arch::x86_64::__rdtscp(&mut aux) }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn read_tsc() -> u64 {
+    panic!("--reference=tsc is only implemented on x86_64");
+}
+
+/// Opens a perf counter of the calling thread's CPU cycles, returning its file descriptor.
+#[cfg(target_os = "linux")]
+fn open_cycles() -> Result<i32, String> {
+    use crate::plat_unixes::libc;
+
+    // perf_event_attr as of PERF_ATTR_SIZE_VER0, which is all we need: the type, its size, the
+    // config, and the flags word (in which everything off means enabled and counting the kernel
+    // too).
+    const PERF_TYPE_HARDWARE: u32 = 0;
+    const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+    const PERF_ATTR_SIZE_VER0: u32 = 64;
+
+    let mut attr = [0u8; PERF_ATTR_SIZE_VER0 as usize];
+    attr[0..4].copy_from_slice(&PERF_TYPE_HARDWARE.to_ne_bytes());
+    attr[4..8].copy_from_slice(&PERF_ATTR_SIZE_VER0.to_ne_bytes());
+    attr[8..16].copy_from_slice(&PERF_COUNT_HW_CPU_CYCLES.to_ne_bytes());
+    // pid 0 and cpu -1: this thread, on whichever CPU it runs.
+    let fd = unsafe { libc::syscall(libc::SYS_perf_event_open, attr.as_ptr(), 0, -1, -1, 0) };
+    if fd < 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(format!("--reference=cycles needs a perf cycle counter, but perf_event_open failed: {e} (is there a hardware counter, and does /proc/sys/kernel/perf_event_paranoid allow it?)"));
+    }
+    Ok(fd as i32)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_cycles() -> Result<i32, String> {
+    Err("--reference=cycles is only implemented on Linux".to_string())
+}
+
+/// Reads this thread's cycle counter, opening it the first time the thread asks.
+#[cfg(target_os = "linux")]
+fn read_cycles() -> u64 {
+    use crate::plat_unixes::libc;
+
+    thread_local! {
+        static FD: i32 = open_cycles().unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    FD.with(|&fd| {
+        let mut count: u64 = 0;
+        let n = unsafe { libc::read(fd, (&mut count as *mut u64).cast(), size_of::<u64>()) };
+        assert_eq!(n, size_of::<u64>() as isize, "--reference=cycles: read: {}", std::io::Error::last_os_error());
+        count
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_cycles() -> u64 {
+    panic!("--reference=cycles is only implemented on Linux");
+}
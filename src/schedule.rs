@@ -0,0 +1,133 @@
+use std::env;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Instant;
+
+use crate::{affinity, new_recorder, print_row, run_epoch, setup_measurement_thread, summarize, StartGuard, warm_up, ClockFn, RunInfo, Summary};
+use crate::affinity::Placement;
+use crate::aggregate;
+use crate::budget;
+use crate::overhead;
+use crate::outliers::{self, Outliers};
+use crate::preemption::{self, Classified};
+use crate::calibration::{self, Calibration};
+use crate::histogram::Recorder;
+use crate::workload::get_workload;
+
+/// How the clocks in the list are run relative to each other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Schedule {
+    /// One clock at a time, so clocks can't interfere with each other.
+    Sequential,
+    /// Every clock on its own thread(s), all at once (the default).
+    Concurrent,
+    /// A single thread takes one sample from each clock in turn, so slow drift in the state of
+    /// the machine hits every clock equally.
+    Interleaved,
+}
+
+/// Returns the `--schedule=sequential|concurrent|interleaved` setting.
+pub fn get_schedule() -> Schedule {
+    for arg in env::args() {
+        if let Some(schedstr) = arg.strip_prefix("--schedule=") {
+            return match schedstr {
+                "sequential" => Schedule::Sequential,
+                "concurrent" => Schedule::Concurrent,
+                "interleaved" => Schedule::Interleaved,
+                _ => panic!("--schedule= takes sequential, concurrent or interleaved, not {schedstr:?}"),
+            };
+        }
+    }
+    Schedule::Concurrent
+}
+
+/// `--schedule=interleaved`: each of `numthreads` threads calibrates every clock, then takes one
+/// sample from each clock in turn until the budget is used up (or, for `--adaptive`, until every
+/// clock has converged). Then prints a row per clock, with the threads' results merged, and
+/// returns the rows.
+pub fn interleave(fns: &[ClockFn], numthreads: usize, placement: &Placement) -> Vec<Summary> {
+    let barrier = Arc::new(Barrier::new(numthreads));
+
+    let handles: Vec<_> = (0..numthreads).map(|i| {
+        let fns = fns.to_vec();
+        let pincpu = placement.cpu_for_all_clocks(i);
+        let start = barrier.clone();
+        thread::spawn(move || {
+            setup_measurement_thread("interleaved measurement thread", pincpu);
+            interleaved_thread(&fns, &start)
+        })
+    }).collect();
+
+    // perclock[i] is every thread's results for fns[i].
+    let mut perclock: Vec<Vec<(Summary, Recorder)>> = fns.iter().map(|_| Vec::with_capacity(numthreads)).collect();
+    for handle in handles {
+        for (i, result) in handle.join().unwrap().into_iter().enumerate() {
+            perclock[i].push(result);
+        }
+    }
+
+    fns.iter().zip(perclock).map(|(cf, results)| {
+        let summary = aggregate::aggregate(cf, results);
+        print_row(&summary);
+        summary
+    }).collect()
+}
+
+fn interleaved_thread(fns: &[ClockFn], start: &Barrier) -> Vec<(Summary, Recorder)> {
+    let mut guard = StartGuard::new(Some(start));
+    let workload = get_workload();
+    let budget = budget::get_budget();
+
+    let cpustart = affinity::current_cpu();
+    let calibrations: Vec<Calibration> = fns.iter().map(calibration::calibrate).collect();
+    let mut recs: Vec<Recorder> = fns.iter().zip(&calibrations).map(|(cf, cal)| new_recorder(cf, cal)).collect();
+    for cf in fns {
+        warm_up(cf, workload);
+    }
+    let overheads: Vec<Option<u64>> = fns.iter().zip(&calibrations).map(|(cf, cal)| overhead::get_subtract_overhead().then(|| overhead::measure_overhead(cf, cal))).collect();
+    let preemption = preemption::get_preemption();
+    let mut classes: Vec<Classified> = fns.iter().map(|_| Classified::new()).collect();
+    let mut outliers: Vec<Option<Outliers>> = fns.iter().zip(&calibrations).map(|(cf, cal)| outliers::get_outliers().map(|settings| Outliers::new(cf, workload, cal, settings))).collect();
+    guard.start();
+
+    let started = run_epoch().elapsed();
+    let startinstant = Instant::now();
+    let mut rounds: u64 = 0;
+    // Convergence is expensive to check, so only check it each time the number of rounds doubles.
+    let mut nextconvergencecheck: u64 = 1_000;
+    loop {
+        for (((cf, rec), c), o) in fns.iter().zip(recs.iter_mut()).zip(classes.iter_mut()).zip(outliers.iter_mut()) {
+            let mut sample = |n, rec: &mut Recorder| match preemption {
+                Some(slack) => preemption::take_classified(cf, workload, n, slack, rec, c),
+                None => (cf.func)(cf.clock, n, workload, rec),
+            };
+            match o {
+                Some(o) => o.take_logged(1, rec, sample),
+                None => sample(1, rec),
+            }
+        }
+        rounds += 1;
+
+        if budget.spent(rounds, startinstant.elapsed()) {
+            for (cf, rec) in fns.iter().zip(&recs) {
+                budget::report_unconverged(cf, &budget, rec);
+            }
+            break;
+        }
+        if rounds == nextconvergencecheck {
+            if recs.iter().all(|rec| budget.converged(rec)) {
+                break;
+            }
+            nextconvergencecheck *= 2;
+        }
+    }
+    let finished = run_epoch().elapsed();
+    let run = RunInfo { cpus: affinity::format_cpus(cpustart, affinity::current_cpu()), started, finished };
+
+    fns.iter().zip(calibrations).zip(overheads).zip(recs).zip(classes).zip(outliers).map(|(((((cf, calibration), overhead), rec), c), o)| {
+        let mut summary = summarize(cf, calibration, overhead, &rec, run.clone());
+        summary.classes = preemption.map(|_| c);
+        summary.outliers = o;
+        (summary, rec)
+    }).collect()
+}
@@ -0,0 +1,320 @@
+use std::env;
+use std::sync::OnceLock;
+
+use thousands::Separable;
+
+use crate::histogram::Histogram;
+use crate::Summary;
+
+const DEFAULT_ALPHA: f64 = 0.01;
+
+/// Returns the significance level from `--compare` (0.01) or `--compare=ALPHA`, or None if clocks
+/// aren't to be compared.
+pub fn get_compare() -> Option<f64> {
+    static COMPARE: OnceLock<Option<f64>> = OnceLock::new();
+
+    *COMPARE.get_or_init(|| {
+        for arg in env::args() {
+            if arg == "--compare" {
+                return Some(DEFAULT_ALPHA);
+            }
+            if let Some(alphastr) = arg.strip_prefix("--compare=") {
+                return match alphastr.parse::<f64>() {
+                    Ok(alpha) if alpha > 0.0 && alpha < 1.0 => Some(alpha),
+                    _ => panic!("--compare= takes a significance level between 0 and 1, e.g. --compare=0.01, not {alphastr:?}"),
+                };
+            }
+        }
+        None
+    })
+}
+
+/// The result of a two-sided test: the statistic and its p-value.
+struct Test {
+    stat: f64,
+    p: f64,
+}
+
+/// Compares every pair of measurements and prints a verdict line for each, tightest first:
+///
+/// - whether one is significantly tighter than the other, by a Mann-Whitney U test on the
+///   distances of the samples from their own median (so it's about spread, not about speed),
+/// - a two-sample Kolmogorov-Smirnov test of whether the distributions differ at all, and
+/// - Mood's median test of whether the medians differ.
+///
+/// Every test uses the normal or asymptotic approximation, which is fine for the number of
+/// samples we take. With millions of samples even a tiny difference is significant, so look at the
+/// size of the difference (the mad and perc50 columns) too.
+///
+/// As every pair is tested, the p-values of each test are adjusted for the number of pairs with
+/// Holm's method, so that `alpha` bounds the chance of any false verdict rather than of each one.
+pub fn compare(summaries: &[Summary], alpha: f64) {
+    let mut sorted: Vec<&Summary> = summaries.iter().filter(|s| s.hist.as_ref().is_some_and(|h| h.total() > 1)).collect();
+    sorted.sort_by_key(|s| (s.mad, s.perc50));
+
+    let mut pairs = Vec::new();
+    for (i, a) in sorted.iter().enumerate() {
+        for b in &sorted[i + 1..] {
+            let (ha, hb) = (a.hist.as_ref().unwrap(), b.hist.as_ref().unwrap());
+            let spread = mann_whitney(&deviations(ha, a.perc50), &deviations(hb, b.perc50));
+            let ks = kolmogorov_smirnov(ha, hb);
+            let mood = mood_median(ha, hb);
+            pairs.push((a, b, spread, ks, mood));
+        }
+    }
+    let spreadps = holm(pairs.iter().map(|p| p.2.p).collect());
+    let ksps = holm(pairs.iter().map(|p| p.3.p).collect());
+    let moodps = holm(pairs.iter().map(|p| p.4.p).collect());
+
+    for (i, (a, b, spread, ks, _)) in pairs.iter().enumerate() {
+        let (alabel, blabel) = (format!("{} {}", a.fnname, a.clockname), format!("{} {}", b.fnname, b.clockname));
+        let verdict = if spreadps[i] < alpha {
+            // A positive statistic means a's deviations rank higher, i.e. a is looser.
+            let (tight, loose) = if spread.stat < 0.0 { (&alabel, &blabel) } else { (&blabel, &alabel) };
+            format!("{tight} is significantly tighter than {loose} at p<{alpha}")
+        } else {
+            format!("{alabel} vs {blabel}: no detectable difference in spread at p<{alpha}")
+        };
+        println!("compare: {verdict} (Holm-adjusted over {} pairs; spread: Mann-Whitney z={:.2} p={:.2e}; distribution: KS D={:.4} p={:.2e}; medians {} vs {}: Mood p={:.2e})", pairs.len(), spread.stat, spreadps[i], ks.stat, ksps[i], a.perc50.separate_with_commas(), b.perc50.separate_with_commas(), moodps[i]);
+    }
+}
+
+/// Holm's step-down adjustment of a family of p-values, in the order given: the k-th smallest (from
+/// 0) is multiplied by the number of p-values less k, and none is allowed below a smaller one's.
+fn holm(ps: Vec<f64>) -> Vec<f64> {
+    let m = ps.len();
+    let mut order: Vec<usize> = (0..m).collect();
+    order.sort_by(|&a, &b| ps[a].total_cmp(&ps[b]));
+    let mut adjusted = vec![0.0; m];
+    let mut highest: f64 = 0.0;
+    for (k, &i) in order.iter().enumerate() {
+        highest = highest.max((ps[i] * (m - k) as f64).min(1.0));
+        adjusted[i] = highest;
+    }
+    adjusted
+}
+
+/// The distances of the samples from `median`, as (distance, count) in increasing order.
+fn deviations(hist: &Histogram, median: u64) -> Vec<(u64, u64)> {
+    let mut devs: Vec<(u64, u64)> = hist.iter().map(|(v, c)| (v.abs_diff(median), c)).collect();
+    devs.sort_unstable();
+    devs.dedup_by(|later, earlier| {
+        if later.0 == earlier.0 {
+            earlier.1 += later.1;
+            true
+        } else {
+            false
+        }
+    });
+    devs
+}
+
+/// Merges two sorted (value, count) lists into (value, count in a, count in b), in increasing
+/// order of value.
+fn merge(a: &[(u64, u64)], b: &[(u64, u64)]) -> Vec<(u64, u64, u64)> {
+    let mut out = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if j == b.len() || (i < a.len() && a[i].0 < b[j].0) {
+            out.push((a[i].0, a[i].1, 0));
+            i += 1;
+        } else if i == a.len() || b[j].0 < a[i].0 {
+            out.push((b[j].0, 0, b[j].1));
+            j += 1;
+        } else {
+            out.push((a[i].0, a[i].1, b[j].1));
+            i += 1;
+            j += 1;
+        }
+    }
+    out
+}
+
+/// Mann-Whitney U test with midranks for ties and the tie-corrected variance. The statistic is the
+/// z score, positive if a's values tend to be larger than b's.
+fn mann_whitney(a: &[(u64, u64)], b: &[(u64, u64)]) -> Test {
+    let na = a.iter().map(|(_, c)| c).sum::<u64>() as f64;
+    let nb = b.iter().map(|(_, c)| c).sum::<u64>() as f64;
+    let n = na + nb;
+
+    let mut ranksum_a = 0.0;
+    let mut ties = 0.0;
+    let mut below = 0.0;
+    for (_, ca, cb) in merge(a, b) {
+        let t = (ca + cb) as f64;
+        let midrank = below + (t + 1.0) / 2.0;
+        ranksum_a += midrank * ca as f64;
+        ties += t * t * t - t;
+        below += t;
+    }
+
+    let u = ranksum_a - na * (na + 1.0) / 2.0;
+    let mean = na * nb / 2.0;
+    let var = na * nb / 12.0 * ((n + 1.0) - ties / (n * (n - 1.0)));
+    if var <= 0.0 {
+        return Test { stat: 0.0, p: 1.0 };
+    }
+    let z = (u - mean) / var.sqrt();
+    Test { stat: z, p: erfc(z.abs() / std::f64::consts::SQRT_2) }
+}
+
+/// Two-sample Kolmogorov-Smirnov test. The statistic is D, the largest distance between the two
+/// empirical CDFs; the p-value is from the asymptotic Kolmogorov distribution.
+fn kolmogorov_smirnov(a: &Histogram, b: &Histogram) -> Test {
+    let (na, nb) = (a.total() as f64, b.total() as f64);
+    let av: Vec<(u64, u64)> = a.iter().collect();
+    let bv: Vec<(u64, u64)> = b.iter().collect();
+
+    let (mut cdfa, mut cdfb, mut d) = (0.0, 0.0, 0f64);
+    for (_, ca, cb) in merge(&av, &bv) {
+        cdfa += ca as f64 / na;
+        cdfb += cb as f64 / nb;
+        d = d.max((cdfa - cdfb).abs());
+    }
+
+    let en = (na * nb / (na + nb)).sqrt();
+    let lambda = (en + 0.12 + 0.11 / en) * d;
+    Test { stat: d, p: kolmogorov_q(lambda) }
+}
+
+/// Q_KS(lambda) = 2 * sum_{j>=1} (-1)^(j-1) exp(-2 j^2 lambda^2), the probability of a D at least
+/// this large if the distributions are the same.
+fn kolmogorov_q(lambda: f64) -> f64 {
+    if lambda < 0.2 {
+        return 1.0;
+    }
+    let mut sum = 0.0;
+    let mut sign = 1.0;
+    for j in 1..=100 {
+        let term = sign * 2.0 * (-2.0 * (j * j) as f64 * lambda * lambda).exp();
+        sum += term;
+        if term.abs() < 1e-12 {
+            break;
+        }
+        sign = -sign;
+    }
+    sum.clamp(0.0, 1.0)
+}
+
+/// Mood's median test: a chi-squared test (1 degree of freedom) of whether the two sets of samples
+/// have the same proportion above their combined median.
+fn mood_median(a: &Histogram, b: &Histogram) -> Test {
+    let av: Vec<(u64, u64)> = a.iter().collect();
+    let bv: Vec<(u64, u64)> = b.iter().collect();
+    let merged = merge(&av, &bv);
+    let (na, nb) = (a.total() as f64, b.total() as f64);
+    let n = na + nb;
+
+    let mut seen = 0.0;
+    let mut median = 0;
+    for &(v, ca, cb) in &merged {
+        seen += (ca + cb) as f64;
+        if seen >= n / 2.0 {
+            median = v;
+            break;
+        }
+    }
+
+    let abovea = merged.iter().filter(|m| m.0 > median).map(|m| m.1).sum::<u64>() as f64;
+    let aboveb = merged.iter().filter(|m| m.0 > median).map(|m| m.2).sum::<u64>() as f64;
+    let above = abovea + aboveb;
+    if above == 0.0 || above == n {
+        return Test { stat: 0.0, p: 1.0 };
+    }
+
+    let mut chi2 = 0.0;
+    for (observed, expected) in [(abovea, na * above / n), (na - abovea, na * (n - above) / n), (aboveb, nb * above / n), (nb - aboveb, nb * (n - above) / n)] {
+        chi2 += (observed - expected).powi(2) / expected;
+    }
+    Test { stat: chi2, p: erfc((chi2 / 2.0).sqrt()) }
+}
+
+/// The complementary error function, to about 1.2e-7 relative accuracy everywhere (Numerical
+/// Recipes' erfcc, a Chebyshev fit).
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let ans = t * (-z * z - 1.26551223 + t * (1.00002368 + t * (0.37409196 + t * (0.09678418 + t * (-0.18628806 + t * (0.27886807 + t * (-1.13520398 + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277))))))))).exp();
+    if x >= 0.0 { ans } else { 2.0 - ans }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hist_of(values: &[u64]) -> Histogram {
+        let mut hist = Histogram::default();
+        for &v in values {
+            hist.record(v);
+        }
+        hist
+    }
+
+    fn close(a: f64, b: f64, tol: f64) -> bool {
+        (a - b).abs() <= tol
+    }
+
+    #[test]
+    fn erfc_matches_known_values() {
+        for (x, want) in [(0.0, 1.0), (0.5, 0.4795001222), (1.0, 0.1572992071), (2.0, 0.0046777350), (-1.0, 1.8427007929)] {
+            let got = erfc(x);
+            assert!(close(got, want, 2e-7 * want.max(1e-3)), "erfc({x}) = {got}, not {want}");
+        }
+    }
+
+    #[test]
+    fn kolmogorov_q_matches_known_values() {
+        assert_eq!(kolmogorov_q(0.1), 1.0);
+        assert!(close(kolmogorov_q(0.5), 0.9639452436, 1e-9));
+        assert!(close(kolmogorov_q(1.0), 0.2699996716, 1e-9));
+        assert!(close(kolmogorov_q(2.0), 0.0006709252, 1e-9));
+    }
+
+    #[test]
+    fn mann_whitney_without_ties() {
+        let t = mann_whitney(&[(1, 1), (2, 1), (3, 1)], &[(4, 1), (5, 1), (6, 1)]);
+        assert!(close(t.stat, -4.5 / 5.25f64.sqrt(), 1e-12), "{}", t.stat);
+        assert!(close(t.p, 0.0495346134, 1e-6), "{}", t.p);
+    }
+
+    #[test]
+    fn mann_whitney_corrects_for_ties() {
+        // a = 1, 2, 2 and b = 2, 3, 3: U = 1, and the ties take the variance from 5.25 to 4.5.
+        let t = mann_whitney(&[(1, 1), (2, 2)], &[(2, 1), (3, 2)]);
+        assert!(close(t.stat, -3.5 / 4.5f64.sqrt(), 1e-12), "{}", t.stat);
+        assert!(close(t.p, 0.0989601540, 1e-6), "{}", t.p);
+
+        let t = mann_whitney(&[(7, 3)], &[(7, 4)]);
+        assert_eq!((t.stat, t.p), (0.0, 1.0));
+    }
+
+    #[test]
+    fn kolmogorov_smirnov_statistic() {
+        let t = kolmogorov_smirnov(&hist_of(&[1, 2, 3]), &hist_of(&[4, 5, 6]));
+        assert_eq!(t.stat, 1.0);
+
+        let t = kolmogorov_smirnov(&hist_of(&[1, 2, 3, 4]), &hist_of(&[1, 2, 3, 4]));
+        assert_eq!((t.stat, t.p), (0.0, 1.0));
+
+        let t = kolmogorov_smirnov(&hist_of(&[1, 2, 3, 4]), &hist_of(&[3, 4, 5, 6]));
+        assert!(close(t.stat, 0.5, 1e-12));
+    }
+
+    #[test]
+    fn mood_median_of_the_same_samples() {
+        let t = mood_median(&hist_of(&[1, 2, 3, 4]), &hist_of(&[1, 2, 3, 4]));
+        assert!(close(t.stat, 0.0, 1e-12));
+        assert!(close(t.p, 1.0, 1e-6));
+    }
+
+    #[test]
+    fn holm_steps_down_and_stays_monotonic() {
+        let adjusted = holm(vec![0.01, 0.04, 0.03]);
+        for (got, want) in adjusted.iter().zip([0.03, 0.06, 0.06]) {
+            assert!(close(*got, want, 1e-12), "{adjusted:?}");
+        }
+        assert_eq!(holm(vec![0.5, 0.6]), vec![1.0, 1.0]);
+        assert_eq!(holm(vec![0.2]), vec![0.2]);
+        assert!(holm(Vec::new()).is_empty());
+    }
+}
@@ -0,0 +1,102 @@
+use std::env;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use thousands::Separable;
+
+use crate::{affinity, measure, print_row, setup_measurement_thread, ClockFn, Summary};
+use crate::load::{self, LoadKind};
+
+/// Returns the CPU to measure on if `--smt` or `--smt=CPU` was given. With plain `--smt` it's the
+/// first allowed CPU that has an SMT sibling.
+pub fn get_smt_cpu() -> Option<Option<usize>> {
+    for arg in env::args() {
+        if arg == "--smt" {
+            return Some(None);
+        }
+        if let Some(cpustr) = arg.strip_prefix("--smt=") {
+            if let Ok(cpu) = cpustr.parse::<usize>() {
+                return Some(Some(cpu));
+            } else {
+                panic!("--smt=CPU needs a CPU number, not {cpustr:?}");
+            }
+        }
+    }
+    None
+}
+
+/// Returns the loads from `--smt-loads=LIST`, or idle, int, avx and mem.
+fn get_smt_loads() -> Vec<LoadKind> {
+    for arg in env::args() {
+        if let Some(loadsstr) = arg.strip_prefix("--smt-loads=") {
+            match load::parse_load_list(loadsstr) {
+                Ok(loads) => return loads,
+                Err(e) => panic!("{e}"),
+            }
+        }
+    }
+    LoadKind::SMT_DEFAULT.to_vec()
+}
+
+/// `--smt`: measures every clock pinned to one hardware thread while its SMT sibling runs each of
+/// the loads in turn, then compares each clock's tail under load with its tail with the sibling
+/// idle. Returns every measurement's summary, clock by clock.
+pub fn smt(fns: &[ClockFn], cpu: Option<usize>) -> Vec<Summary> {
+    let cpu = cpu.or_else(|| affinity::online_cpus().into_iter().find(|&c| affinity::smt_sibling(c).is_some()));
+    let Some(cpu) = cpu else {
+        println!("smt: no CPU with an SMT sibling found (is SMT turned off?)");
+        return Vec::new();
+    };
+    let Some(sibling) = affinity::smt_sibling(cpu) else {
+        println!("smt: CPU {cpu} has no SMT sibling (is SMT turned off?)");
+        return Vec::new();
+    };
+    let loads = get_smt_loads();
+
+    // results[i] is every load's summary for fns[i]
+    let mut results: Vec<Vec<(LoadKind, Summary)>> = fns.iter().map(|_| Vec::with_capacity(loads.len())).collect();
+
+    for &kind in &loads {
+        println!("smt: measuring on CPU {cpu} with {} load on sibling CPU {sibling}", kind.name());
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let loadstop = stop.clone();
+        let loadhandle = thread::spawn(move || {
+            if let Err(e) = affinity::pin_current_thread(sibling) {
+                eprintln!("smt: couldn't pin the {} load to CPU {sibling}: {e}", kind.name());
+            }
+            load::run_load(kind, &loadstop);
+        });
+
+        for (i, cf) in fns.iter().enumerate() {
+            let cf = *cf;
+            let summary = thread::spawn(move || {
+                setup_measurement_thread(&cf.label(), Some(cpu));
+                measure(&cf, None)
+            }).join().unwrap();
+
+            print_row(&summary);
+            results[i].push((kind, summary));
+        }
+
+        stop.store(true, Ordering::Relaxed);
+        loadhandle.join().unwrap();
+    }
+
+    println!();
+    println!("{:>38} {:>14} {:>5} {:>7} {:>7} {:>14} {:>11} {:>8} {:>8}", "fnname", "clock", "load", "perc50", "perc95", "max", "stddev", "perc95x", "maxx");
+    println!("{:>38} {:>14} {:>5} {:>7} {:>7} {:>14} {:>11} {:>8} {:>8}", "------", "-----", "----", "------", "------", "---", "------", "-------", "----");
+
+    for (cf, perload) in fns.iter().zip(&results) {
+        // Inflation is relative to the sibling being idle, or to the first load if idle wasn't run.
+        let (_, base) = perload.iter().find(|(k, _)| *k == LoadKind::Idle).unwrap_or(&perload[0]);
+        for (kind, s) in perload {
+            let p95x = s.perc95 as f64 / base.perc95.max(1) as f64;
+            let maxx = s.max as f64 / base.max.max(1) as f64;
+            println!("{:>38} {:>14} {:>5} {:>7} {:>7} {:>14} {:>11} {:>7.2}x {:>7.2}x", cf.fnname, cf.clockname, kind.name(), s.perc50.separate_with_commas(), s.perc95.separate_with_commas(), s.max.separate_with_commas(), (s.stddev as u128).separate_with_commas(), p95x, maxx);
+        }
+    }
+
+    results.into_iter().flatten().map(|(_, s)| s).collect()
+}
@@ -0,0 +1,73 @@
+use std::env;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+use crate::affinity;
+use crate::load::{self, LoadKind};
+
+/// Background load threads started by `--stress`. They keep running until this is dropped.
+pub struct Stress {
+    stop: Arc<AtomicBool>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Stress {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for handle in self.handles.drain(..) {
+            handle.join().unwrap();
+        }
+    }
+}
+
+/// Parses `KIND[:THREADS],...`, e.g. `cpu,syscall:2`. Without a thread count a load gets one thread
+/// per allowed CPU.
+fn parse_stress(s: &str, ncpus: usize) -> Result<Vec<(LoadKind, usize)>, String> {
+    s.split(',').filter(|p| !p.is_empty()).map(|part| {
+        let (kindstr, threadsstr) = match part.split_once(':') {
+            Some((k, n)) => (k, Some(n)),
+            None => (part, None),
+        };
+        let kind = LoadKind::parse(kindstr)?;
+        let threads = match threadsstr {
+            Some(n) => n.parse::<usize>().map_err(|e| format!("bad stress thread count {n:?}: {e}"))?,
+            None => ncpus,
+        };
+        Ok((kind, threads))
+    }).collect()
+}
+
+/// Starts the loads given with `--stress=KIND[:THREADS],...` (any of cpu, avx, mem, syscall,
+/// timeofday, pagefault), spreading each kind's threads round-robin over the allowed CPUs.
+pub fn start_stress() -> Option<Stress> {
+    let stressstr = env::args().find_map(|arg| arg.strip_prefix("--stress=").map(str::to_string))?;
+
+    let cpus = affinity::online_cpus();
+    let loads = match parse_stress(&stressstr, cpus.len()) {
+        Ok(loads) => loads,
+        Err(e) => panic!("{e}"),
+    };
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut handles = Vec::new();
+    let mut described = Vec::new();
+
+    for (kind, threads) in loads {
+        described.push(format!("{} x{threads}", kind.name()));
+        for i in 0..threads {
+            let cpu = cpus[i % cpus.len()];
+            let stop = stop.clone();
+            handles.push(thread::spawn(move || {
+                if let Err(e) = affinity::pin_current_thread(cpu) {
+                    eprintln!("stress: couldn't pin a {} thread to CPU {cpu}, leaving it unpinned: {e}", kind.name());
+                }
+                load::run_load(kind, &stop);
+            }));
+        }
+    }
+
+    println!("stress: {}", described.join(", "));
+
+    Some(Stress { stop, handles })
+}
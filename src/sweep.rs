@@ -0,0 +1,151 @@
+use std::env;
+
+use thousands::Separable;
+
+use std::sync::Barrier;
+
+use crate::{ClockFn, StartGuard, new_recorder, warm_up};
+use crate::budget;
+use crate::histogram::Recorder;
+use crate::calibration;
+use crate::affinity;
+use crate::aggregate::merge_cpus;
+use crate::workload::{get_workload, WorkloadKind};
+
+const DEFAULT_SWEEP_MAX: u64 = 1024;
+
+/// The smallest MAX that gives the three sizes (1, 2, 4) a line needs.
+const MIN_SWEEP_MAX: u64 = 4;
+
+/// Returns the workload sizes to sweep over if `--sweep` or `--sweep=MAX` was given: 1, 2, 4, ...
+/// up to and including MAX (default 1024), or an error if MAX is too small to fit a line through
+/// or the workload has no size.
+pub fn get_sweep_sizes() -> Result<Option<Vec<u64>>, String> {
+    let mut max = None;
+    for arg in env::args() {
+        if arg == "--sweep" {
+            max = Some(DEFAULT_SWEEP_MAX);
+        } else if let Some(maxstr) = arg.strip_prefix("--sweep=") {
+            match maxstr.parse::<u64>() {
+                Ok(argmax) if argmax >= MIN_SWEEP_MAX => max = Some(argmax),
+                _ => return Err(format!("--sweep=MAX needs a number of at least {MIN_SWEEP_MAX} to have enough points to fit a line, not {maxstr:?}")),
+            }
+        }
+    }
+    let Some(max) = max else {
+        return Ok(None);
+    };
+
+    if get_workload().kind == WorkloadKind::Empty {
+        return Err("--sweep needs a workload that has a size, and \"empty\" doesn't".to_string());
+    }
+
+    let mut sizes = Vec::new();
+    let mut size = 1;
+    while size <= max {
+        sizes.push(size);
+        size *= 2;
+    }
+    if *sizes.last().unwrap() != max {
+        sizes.push(max);
+    }
+
+    Ok(Some(sizes))
+}
+
+/// A least-squares fit of `y = intercept + slope * x`.
+pub struct LineFit {
+    pub intercept: f64,
+    pub slope: f64,
+    pub r2: f64,
+    /// Root-mean-square of the residuals.
+    pub residrms: f64,
+    /// Largest residual relative to the fitted value, as a fraction.
+    pub maxrelresid: f64,
+}
+
+pub fn fit_line(points: &[(f64, f64)]) -> LineFit {
+    let n = points.len() as f64;
+    let meanx = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let meany = points.iter().map(|p| p.1).sum::<f64>() / n;
+
+    let mut sxx = 0f64;
+    let mut sxy = 0f64;
+    let mut syy = 0f64;
+    for (x, y) in points {
+        sxx += (x - meanx) * (x - meanx);
+        sxy += (x - meanx) * (y - meany);
+        syy += (y - meany) * (y - meany);
+    }
+    let slope = sxy / sxx;
+    let intercept = meany - slope * meanx;
+
+    let mut ssres = 0f64;
+    let mut maxrelresid = 0f64;
+    for (x, y) in points {
+        let fitted = intercept + slope * x;
+        let resid = y - fitted;
+        ssres += resid * resid;
+        if fitted != 0f64 {
+            maxrelresid = maxrelresid.max((resid / fitted).abs());
+        }
+    }
+    let r2 = if syy > 0f64 { 1f64 - ssres / syy } else { 1f64 };
+
+    LineFit { intercept, slope, r2, residrms: (ssres / n).sqrt(), maxrelresid }
+}
+
+pub fn print_header() {
+    println!("{:>38} {:>14} {:>5} {:>7} {:>11} {:>11} {:>10} {:>11} {:>9}", "fnname", "clock", "cpu", "nsizes", "intercept", "slope", "r2", "residrms", "maxresid");
+    println!("{:>38} {:>14} {:>5} {:>7} {:>11} {:>11} {:>10} {:>11} {:>9}", "------", "-----", "---", "------", "---------", "-----", "--", "--------", "--------");
+}
+
+/// One thread's sweep of one clock: the samples at each size, and the CPU(s) it ran on.
+pub struct Swept {
+    pub recs: Vec<Recorder>,
+    pub cpus: String,
+}
+
+/// Measures the clock once per workload size, for `row()` to fit a line through. The budget
+/// (`--iters=`, `--duration=` or the `--adaptive=` limit) is shared out evenly between the sizes,
+/// so a sweep costs about as much as a plain run.
+pub fn sweep(cf: &ClockFn, sizes: &[u64], start: Option<&Barrier>) -> Swept {
+    let mut guard = StartGuard::new(start);
+    let workload = get_workload();
+    let budget = budget::get_budget().split(sizes.len());
+
+    let cpustart = affinity::current_cpu();
+    let calibration = calibration::calibrate(cf);
+    warm_up(cf, workload);
+    let mut recs: Vec<Recorder> = sizes.iter().map(|_| new_recorder(cf, &calibration)).collect();
+    guard.start();
+
+    for (&size, rec) in sizes.iter().zip(&mut recs) {
+        budget::take_samples(cf, &workload.with_size(size), &budget, rec);
+    }
+
+    Swept { recs, cpus: affinity::format_cpus(cpustart, affinity::current_cpu()) }
+}
+
+/// Merges every thread's sweep of the clock, size by size, and fits a line through the median
+/// duration at each size (medians, so that rare huge outliers don't drag the line around),
+/// returning the row to print.
+pub fn row(cf: &ClockFn, sizes: &[u64], swept: Vec<Swept>) -> String {
+    let cpus = merge_cpus(swept.iter().map(|s| s.cpus.as_str()));
+    let mut swept = swept.into_iter();
+    let mut recs = swept.next().expect("a sweep with no threads").recs;
+    for other in swept {
+        for (rec, otherrec) in recs.iter_mut().zip(&other.recs) {
+            rec.merge(otherrec);
+        }
+    }
+
+    let points: Vec<(f64, f64)> = sizes.iter().zip(&recs).filter(|(_, rec)| rec.count > 0).map(|(&size, rec)| (size as f64, rec.quantile(0.5) as f64)).collect();
+    if points.len() < 3 {
+        return format!("{:>38} {:>14} {cpus:>5} {:>7} (too few sizes produced samples to fit a line)", cf.fnname, cf.clockname, points.len());
+    }
+
+    let fit = fit_line(&points);
+
+    format!("{:>38} {:>14} {cpus:>5} {:>7} {:>11} {:>11.3} {:>10.6} {:>11} {:>8.2}%", cf.fnname, cf.clockname, points.len(), (fit.intercept.round() as i64).separate_with_commas(), fit.slope, fit.r2, (fit.residrms.round() as u64).separate_with_commas(), fit.maxrelresid * 100f64)
+}
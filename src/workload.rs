@@ -0,0 +1,250 @@
+use std::cell::Cell;
+use std::hint::black_box;
+use std::sync::{Arc, OnceLock};
+use std::thread::{sleep, yield_now};
+use std::time::Duration;
+use std::env;
+
+/// Size of the pointer-chase ring, in `usize` slots. 8 Mi slots is 64 MiB on 64-bit targets, which
+/// is comfortably larger than the last-level cache of every machine we've measured on.
+const CHASE_SLOTS: usize = 8 * 1024 * 1024;
+
+/// Number of bytes requested by each allocation of the `alloc` workload.
+const ALLOC_BYTES: usize = 64;
+
+thread_local! {
+    // Where this thread's pointer chase left off.
+    static CHASE_POS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// What gets run between the two clock reads of every sample.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkloadKind {
+    /// The original `dummy_func()`, repeated `size` times.
+    Dummy,
+    /// Nothing at all, so the sample is just the cost of reading the clock twice.
+    Empty,
+    /// `size` iterations of a dependent integer loop.
+    Spin,
+    /// `size` dependent loads chasing pointers around a randomly-permuted 64 MiB ring.
+    Chase,
+    /// `size` `getpid` system calls.
+    Getpid,
+    /// `size` heap allocations (and frees) of `ALLOC_BYTES` bytes.
+    Alloc,
+    /// `size` calls to `thread::yield_now()`.
+    Yield,
+    /// One `thread::sleep()` of `size` nanoseconds.
+    Sleep,
+}
+
+impl WorkloadKind {
+    pub const ALL: [WorkloadKind; 8] = [WorkloadKind::Dummy, WorkloadKind::Empty, WorkloadKind::Spin, WorkloadKind::Chase, WorkloadKind::Getpid, WorkloadKind::Alloc, WorkloadKind::Yield, WorkloadKind::Sleep];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WorkloadKind::Dummy => "dummy",
+            WorkloadKind::Empty => "empty",
+            WorkloadKind::Spin => "spin",
+            WorkloadKind::Chase => "chase",
+            WorkloadKind::Getpid => "getpid",
+            WorkloadKind::Alloc => "alloc",
+            WorkloadKind::Yield => "yield",
+            WorkloadKind::Sleep => "sleep",
+        }
+    }
+
+    /// The size used when none is given on the command line.
+    pub fn default_size(self) -> u64 {
+        match self {
+            WorkloadKind::Dummy => 1,
+            WorkloadKind::Empty => 0,
+            WorkloadKind::Spin => 1_000,
+            WorkloadKind::Chase => 100,
+            WorkloadKind::Getpid => 1,
+            WorkloadKind::Alloc => 1,
+            WorkloadKind::Yield => 1,
+            WorkloadKind::Sleep => 1_000,
+        }
+    }
+}
+
+/// A workload kind together with its size. The meaning of `size` depends on the kind, see
+/// `WorkloadKind`.
+#[derive(Clone, Debug)]
+pub struct Workload {
+    pub kind: WorkloadKind,
+    pub size: u64,
+    chase: Option<Arc<[usize]>>,
+}
+
+impl Workload {
+    pub fn new(kind: WorkloadKind, size: u64) -> Workload {
+        let chase = if kind == WorkloadKind::Chase { Some(chase_ring()) } else { None };
+        Workload { kind, size, chase }
+    }
+
+    /// Returns the same kind of workload with a different size, sharing any pre-built state (such
+    /// as the pointer-chase ring).
+    pub fn with_size(&self, size: u64) -> Workload {
+        Workload { kind: self.kind, size, chase: self.chase.clone() }
+    }
+
+    /// Parses `KIND` or `KIND:SIZE`, e.g. `spin:5000`.
+    pub fn parse(s: &str) -> Result<Workload, String> {
+        let (kindstr, sizestr) = match s.split_once(':') {
+            Some((k, n)) => (k, Some(n)),
+            None => (s, None),
+        };
+
+        let Some(kind) = WorkloadKind::ALL.into_iter().find(|k| k.name() == kindstr) else {
+            let names: Vec<&str> = WorkloadKind::ALL.iter().map(|k| k.name()).collect();
+            return Err(format!("unknown workload {kindstr:?}, expected one of: {}", names.join(", ")));
+        };
+
+        let size = match sizestr {
+            Some(n) => n.replace('_', "").parse::<u64>().map_err(|e| format!("bad workload size {n:?}: {e}"))?,
+            None => kind.default_size(),
+        };
+
+        Ok(Workload::new(kind, size))
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.kind == WorkloadKind::Dummy && self.size == WorkloadKind::Dummy.default_size()
+    }
+
+    #[inline(never)]
+    pub fn run(&self) -> i64 {
+        match self.kind {
+            WorkloadKind::Dummy => {
+                let mut acc = 0;
+                for _ in 0..self.size {
+                    acc ^= dummy_func();
+                }
+                acc
+            }
+            WorkloadKind::Empty => 0,
+            WorkloadKind::Spin => {
+                let mut acc: i64 = 0;
+                for i in 0..self.size {
+                    acc = black_box(acc.wrapping_mul(31).wrapping_add(i as i64));
+                }
+                acc
+            }
+            WorkloadKind::Chase => {
+                let ring = self.chase.as_ref().unwrap();
+                // Carry on from where the previous run on this thread stopped, otherwise every run
+                // would walk the same (by now cached) prefix of the ring.
+                let mut p = CHASE_POS.get();
+                for _ in 0..self.size {
+                    p = black_box(ring[p]);
+                }
+                CHASE_POS.set(p);
+                p as i64
+            }
+            WorkloadKind::Getpid => {
+                let mut acc = 0;
+                for _ in 0..self.size {
+                    acc ^= getpid();
+                }
+                acc
+            }
+            WorkloadKind::Alloc => {
+                let mut acc = 0;
+                for _ in 0..self.size {
+                    let v: Vec<u8> = black_box(Vec::with_capacity(ALLOC_BYTES));
+                    acc ^= v.as_ptr() as i64;
+                }
+                acc
+            }
+            WorkloadKind::Yield => {
+                for _ in 0..self.size {
+                    yield_now();
+                }
+                0
+            }
+            WorkloadKind::Sleep => {
+                sleep(Duration::from_nanos(self.size));
+                0
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Workload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.kind.name(), self.size)
+    }
+}
+
+#[inline(never)]
+pub fn dummy_func() -> i64 {
+    // When I make this code a little faster/simpler then cputime on Macos starts telling me
+    // that it took 0 nanoseconds. 🤔
+    let mut a = Arc::new(0);
+    for i in 0..30 {
+        for j in 0..29 {
+            *Arc::make_mut(&mut a) ^= black_box(i * j);
+        }
+    }
+
+    *a
+}
+
+#[cfg(target_os = "linux")]
+pub fn getpid() -> i64 {
+    use crate::plat_unixes::libc;
+    // Go through syscall() so that no libc is tempted to cache the answer.
+    unsafe { libc::syscall(libc::SYS_getpid) }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn getpid() -> i64 {
+    use crate::plat_unixes::libc;
+    unsafe { libc::getpid() as i64 }
+}
+
+#[cfg(not(unix))]
+pub fn getpid() -> i64 {
+    std::process::id() as i64
+}
+
+/// Builds a single random cycle through `CHASE_SLOTS` slots (Sattolo's algorithm), so that every
+/// load depends on the previous one and the hardware prefetcher can't guess the next address.
+fn chase_ring() -> Arc<[usize]> {
+    static RING: OnceLock<Arc<[usize]>> = OnceLock::new();
+
+    RING.get_or_init(|| {
+        let mut ring: Vec<usize> = (0..CHASE_SLOTS).collect();
+        let mut x: u64 = 0x9E37_79B9_7F4A_7C15;
+        for i in (1..CHASE_SLOTS).rev() {
+            // xorshift64
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            let j = (x % i as u64) as usize;
+            ring.swap(i, j);
+        }
+        ring.into()
+    }).clone()
+}
+
+/// Returns the workload selected with `--workload=KIND[:SIZE]`, or the original `dummy_func()` if
+/// there isn't one.
+pub fn get_workload() -> &'static Workload {
+    static WORKLOAD: OnceLock<Workload> = OnceLock::new();
+
+    WORKLOAD.get_or_init(|| {
+        for arg in env::args() {
+            if let Some(wlstr) = arg.strip_prefix("--workload=") {
+                match Workload::parse(wlstr) {
+                    Ok(wl) => return wl,
+                    Err(e) => panic!("{e}"),
+                }
+            }
+        }
+
+        Workload::new(WorkloadKind::Dummy, WorkloadKind::Dummy.default_size())
+    })
+}